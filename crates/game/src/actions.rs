@@ -0,0 +1,464 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use bevy::input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel};
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::settings::{config_path, load_config, save_config};
+
+/// The config file the bindings are kept in.
+const BINDINGS_FILE: &str = "input.ron";
+/// How far an action bound to an analog input has to be pushed to count as pressed.
+const PRESS_THRESHOLD: f32 = 0.5;
+/// Pixels of scrolling that count as scrolling a line, for touchpads.
+const PIXELS_PER_LINE: f32 = 20.;
+
+/// Something the player can do, independent of the key or button that does it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Action {
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    Jump,
+    Grab,
+    /// Throws whatever the player holds, the harder the longer it is held down.
+    Throw,
+    /// Uses whatever the player looks at, such as opening a door, if held down long enough.
+    Interact,
+    /// Moving what the player holds, along with the mouse wheel.
+    HoldCloser,
+    HoldFarther,
+    /// Turns what the player holds with the mouse or look stick, instead of looking around.
+    Rotate,
+    /// Turns what the player holds back upright.
+    ResetRotation,
+    /// Looking around with anything but the mouse, which always looks around.
+    LookUp,
+    LookDown,
+    LookLeft,
+    LookRight,
+    /// Opens or closes the pause menu, which lets go of the mouse cursor.
+    Menu,
+    /// Takes the mouse cursor back.
+    CaptureCursor,
+    /// Toggles whether the player is ready for the match to start.
+    Ready,
+}
+
+/// Which way an axis has to be pushed to trigger an action.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AxisDirection {
+    Positive,
+    Negative,
+}
+
+/// An input that triggers an action.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    GamepadButton(GamepadButtonType),
+    /// An analog stick or trigger, on any connected gamepad.
+    GamepadAxis(GamepadAxisType, AxisDirection),
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Binding::Key(key) => write!(f, "{:?}", key),
+            Binding::Mouse(button) => write!(f, "the {:?} mouse button", button),
+            Binding::GamepadButton(button) => write!(f, "the {:?} gamepad button", button),
+            Binding::GamepadAxis(axis, AxisDirection::Positive) => write!(f, "{:?} up", axis),
+            Binding::GamepadAxis(axis, AxisDirection::Negative) => write!(f, "{:?} down", axis),
+        }
+    }
+}
+
+/// Which inputs trigger which actions and how analog sticks respond, kept in the `input.ron`
+/// config file.
+#[derive(Resource, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct InputBindings {
+    #[serde(deserialize_with = "overlay_default_bindings")]
+    pub bindings: BTreeMap<Action, Vec<Binding>>,
+    /// How far a stick has to be pushed before it does anything, from 0 to 1.
+    pub dead_zone: f32,
+    /// Exponent applied to how far a stick is pushed past the dead zone. Above 1 gives finer
+    /// control near the centre, 1 responds linearly.
+    pub response_curve: f32,
+    /// How fast a stick pushed all the way looks around, in degrees per second.
+    pub stick_look_speed: f32,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        use self::AxisDirection::*;
+
+        let bindings = vec![
+            (
+                Action::MoveForward,
+                vec![
+                    Binding::Key(KeyCode::W),
+                    Binding::GamepadAxis(GamepadAxisType::LeftStickY, Positive),
+                ],
+            ),
+            (
+                Action::MoveBack,
+                vec![
+                    Binding::Key(KeyCode::S),
+                    Binding::GamepadAxis(GamepadAxisType::LeftStickY, Negative),
+                ],
+            ),
+            (
+                Action::MoveLeft,
+                vec![
+                    Binding::Key(KeyCode::A),
+                    Binding::GamepadAxis(GamepadAxisType::LeftStickX, Negative),
+                ],
+            ),
+            (
+                Action::MoveRight,
+                vec![
+                    Binding::Key(KeyCode::D),
+                    Binding::GamepadAxis(GamepadAxisType::LeftStickX, Positive),
+                ],
+            ),
+            (
+                Action::Jump,
+                vec![
+                    Binding::Key(KeyCode::Space),
+                    Binding::GamepadButton(GamepadButtonType::South),
+                ],
+            ),
+            (
+                Action::Grab,
+                vec![
+                    Binding::Key(KeyCode::E),
+                    Binding::GamepadButton(GamepadButtonType::West),
+                ],
+            ),
+            (
+                Action::Throw,
+                vec![
+                    Binding::Mouse(MouseButton::Left),
+                    Binding::GamepadButton(GamepadButtonType::RightTrigger2),
+                ],
+            ),
+            (
+                Action::Interact,
+                vec![
+                    Binding::Key(KeyCode::F),
+                    Binding::GamepadButton(GamepadButtonType::East),
+                ],
+            ),
+            (
+                Action::HoldCloser,
+                vec![Binding::GamepadButton(GamepadButtonType::DPadDown)],
+            ),
+            (
+                Action::HoldFarther,
+                vec![Binding::GamepadButton(GamepadButtonType::DPadUp)],
+            ),
+            (
+                Action::Rotate,
+                vec![
+                    Binding::Mouse(MouseButton::Right),
+                    Binding::GamepadButton(GamepadButtonType::LeftTrigger),
+                ],
+            ),
+            (
+                Action::ResetRotation,
+                vec![
+                    Binding::Key(KeyCode::T),
+                    Binding::GamepadButton(GamepadButtonType::RightThumb),
+                ],
+            ),
+            (
+                Action::LookUp,
+                vec![Binding::GamepadAxis(GamepadAxisType::RightStickY, Positive)],
+            ),
+            (
+                Action::LookDown,
+                vec![Binding::GamepadAxis(GamepadAxisType::RightStickY, Negative)],
+            ),
+            (
+                Action::LookLeft,
+                vec![Binding::GamepadAxis(GamepadAxisType::RightStickX, Negative)],
+            ),
+            (
+                Action::LookRight,
+                vec![Binding::GamepadAxis(GamepadAxisType::RightStickX, Positive)],
+            ),
+            (
+                Action::Menu,
+                vec![
+                    Binding::Key(KeyCode::Escape),
+                    Binding::GamepadButton(GamepadButtonType::Start),
+                ],
+            ),
+            (
+                Action::CaptureCursor,
+                vec![
+                    Binding::Mouse(MouseButton::Left),
+                    Binding::Mouse(MouseButton::Right),
+                ],
+            ),
+            (
+                Action::Ready,
+                vec![
+                    Binding::Key(KeyCode::R),
+                    Binding::GamepadButton(GamepadButtonType::North),
+                ],
+            ),
+        ];
+
+        Self {
+            bindings: bindings.into_iter().collect(),
+            dead_zone: 0.15,
+            response_curve: 2.,
+            stick_look_speed: 180.,
+        }
+    }
+}
+
+/// Reads saved bindings over the default ones, so actions added since the file was written are
+/// still bound. Actions the game doesn't have anymore are skipped.
+fn overlay_default_bindings<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<Action, Vec<Binding>>, D::Error> {
+    let mut bindings = InputBindings::default().bindings;
+    let saved = BTreeMap::<SavedAction, Vec<Binding>>::deserialize(deserializer)?;
+    for (action, action_bindings) in saved {
+        match action {
+            SavedAction::Known(action) => {
+                bindings.insert(action, action_bindings);
+            }
+            SavedAction::Unknown(name) => warn!("ignoring the bindings of unknown action {}", name),
+        }
+    }
+    Ok(bindings)
+}
+
+/// An action named in a saved config file.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SavedAction {
+    Known(Action),
+    Unknown(String),
+}
+
+impl<'de> Deserialize<'de> for SavedAction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl Visitor<'_> for NameVisitor {
+            type Value = String;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("the name of an action")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<String, E> {
+                Ok(name.to_string())
+            }
+        }
+
+        let name = deserializer.deserialize_identifier(NameVisitor)?;
+        let action = Action::deserialize(IntoDeserializer::<de::value::Error>::into_deserializer(
+            name.as_str(),
+        ));
+        Ok(match action {
+            Ok(action) => SavedAction::Known(action),
+            Err(_) => SavedAction::Unknown(name),
+        })
+    }
+}
+
+impl InputBindings {
+    pub fn load() -> Self {
+        load_config(&config_path(BINDINGS_FILE))
+    }
+
+    pub fn save(&self) {
+        save_config(&config_path(BINDINGS_FILE), self);
+    }
+
+    /// Names the first input bound to `action`, for telling the player what to press. Falls back
+    /// to `fallback` when nothing is bound to it.
+    pub fn describe(&self, action: Action, fallback: &str) -> String {
+        self.bindings
+            .get(&action)
+            .and_then(|bindings| bindings.first())
+            .map_or(fallback.to_string(), |binding| binding.to_string())
+    }
+
+    /// Applies the dead zone and response curve to a stick pushed `value` of the way.
+    fn stick_response(&self, value: f32) -> f32 {
+        if value <= self.dead_zone {
+            return 0.;
+        }
+        let value = ((value - self.dead_zone) / (1. - self.dead_zone)).min(1.);
+        value.powf(self.response_curve)
+    }
+}
+
+/// How strongly each action is triggered this frame. The player systems read this instead of
+/// the keyboard, mouse and gamepads.
+#[derive(Resource, Default)]
+pub struct ActionState {
+    values: HashMap<Action, f32>,
+    just_pressed: HashSet<Action>,
+    /// How far the mouse moved this frame, in pixels.
+    pub mouse_motion: Vec2,
+    /// How far the mouse wheel scrolled this frame, in lines, positive away from the player.
+    pub scroll: f32,
+}
+
+impl ActionState {
+    /// How strongly `action` is triggered, from 0 to 1. Buttons and keys are either.
+    pub fn value(&self, action: Action) -> f32 {
+        self.values.get(&action).copied().unwrap_or(0.)
+    }
+
+    pub fn pressed(&self, action: Action) -> bool {
+        self.value(action) >= PRESS_THRESHOLD
+    }
+
+    /// Whether `action` started being pressed this frame.
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    /// `positive` minus `negative`, from -1 to 1.
+    pub fn axis(&self, negative: Action, positive: Action) -> f32 {
+        self.value(positive) - self.value(negative)
+    }
+}
+
+/// Label for the system that updates the `ActionState`, in `CoreStage::PreUpdate`.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, SystemLabel)]
+pub struct ActionSystem;
+
+/// Maps keyboard, mouse and gamepad input to `Action`s
+pub struct ActionPlugin;
+
+impl Plugin for ActionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InputBindings::load())
+            .init_resource::<ActionState>()
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_actions.label(ActionSystem).after(InputSystem),
+            );
+    }
+}
+
+fn update_actions(
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    bindings: Res<InputBindings>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    mut actions: ResMut<ActionState>,
+) {
+    let value = |binding: &Binding| match *binding {
+        Binding::Key(key) => keys.pressed(key) as u8 as f32,
+        Binding::Mouse(button) => mouse_buttons.pressed(button) as u8 as f32,
+        Binding::GamepadButton(button) => gamepads
+            .iter()
+            .any(|gamepad| gamepad_buttons.pressed(GamepadButton::new(gamepad, button)))
+            as u8 as f32,
+        Binding::GamepadAxis(axis, direction) => gamepads
+            .iter()
+            .filter_map(|gamepad| gamepad_axes.get(GamepadAxis::new(gamepad, axis)))
+            .map(|value| match direction {
+                AxisDirection::Positive => value,
+                AxisDirection::Negative => -value,
+            })
+            .map(|value| bindings.stick_response(value))
+            .fold(0., f32::max),
+    };
+    // catches a press and release that both happened since the last frame
+    let tapped = |binding: &Binding| match *binding {
+        Binding::Key(key) => keys.just_pressed(key),
+        Binding::Mouse(button) => mouse_buttons.just_pressed(button),
+        Binding::GamepadButton(button) => gamepads
+            .iter()
+            .any(|gamepad| gamepad_buttons.just_pressed(GamepadButton::new(gamepad, button))),
+        Binding::GamepadAxis(..) => false,
+    };
+
+    let actions = &mut *actions;
+    let was_pressed: HashSet<Action> = actions
+        .values
+        .iter()
+        .filter(|(_, value)| **value >= PRESS_THRESHOLD)
+        .map(|(action, _)| *action)
+        .collect();
+    actions.values.clear();
+    actions.just_pressed.clear();
+    for (action, action_bindings) in bindings.bindings.iter() {
+        let strength = action_bindings.iter().map(value).fold(0., f32::max);
+        if strength > 0. {
+            actions.values.insert(*action, strength);
+        }
+        let pressed = strength >= PRESS_THRESHOLD && !was_pressed.contains(action);
+        if pressed || action_bindings.iter().any(tapped) {
+            actions.just_pressed.insert(*action);
+        }
+    }
+
+    actions.mouse_motion = mouse_motion.iter().map(|motion| motion.delta).sum();
+    actions.scroll = mouse_wheel
+        .iter()
+        .map(|wheel| match wheel.unit {
+            MouseScrollUnit::Line => wheel.y,
+            MouseScrollUnit::Pixel => wheel.y / PIXELS_PER_LINE,
+        })
+        .sum();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saved_bindings_overlay_defaults() {
+        // written before Throw existed and Menu was still called ReleaseCursor
+        let saved = r#"(
+            bindings: {
+                MoveForward: [Key(Up)],
+                Grab: [],
+                ReleaseCursor: [Key(Escape)],
+            },
+            dead_zone: 0.3,
+        )"#;
+        let loaded: InputBindings = ron::from_str(saved).unwrap();
+        let defaults = InputBindings::default();
+
+        assert_eq!(
+            loaded.bindings[&Action::MoveForward],
+            vec![Binding::Key(KeyCode::Up)]
+        );
+        // unbinding an action sticks
+        assert!(loaded.bindings[&Action::Grab].is_empty());
+        assert_eq!(
+            loaded.bindings[&Action::Throw],
+            defaults.bindings[&Action::Throw]
+        );
+        assert_eq!(
+            loaded.bindings[&Action::Menu],
+            defaults.bindings[&Action::Menu]
+        );
+        assert_eq!(loaded.bindings.len(), defaults.bindings.len());
+        assert_eq!(loaded.dead_zone, 0.3);
+        assert_eq!(loaded.response_curve, defaults.response_curve);
+    }
+}
@@ -0,0 +1,414 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use net::{Message, NetworkEvent, NetworkResource, NetworkTick, PlayerId, Transport};
+
+use crate::character::CharacterController;
+use crate::network::*;
+use crate::player::*;
+
+/// Fastest an object simulated by a client may move before its state is rejected.
+const MAX_OBJECT_SPEED: f32 = 30.;
+/// Furthest an object simulated by a client may be from that client's body.
+const MAX_OWNER_DISTANCE: f32 = GRAB_REACH + 3.;
+/// Distance allowed on top of `MAX_OBJECT_SPEED` between two accepted states.
+const POSITION_SLACK: f32 = 0.5;
+/// How far the squared length of a rotation sent by a client may be from 1, the length of a
+/// unit quaternion.
+const ROTATION_SLACK: f32 = 1e-3;
+/// Rejected states a client may send before the server takes authority back.
+const MAX_STRIKES: u32 = 5;
+/// How long a client keeps authority over an object after it stopped touching it.
+const TOUCH_AUTHORITY_SECS: f64 = 0.5;
+/// How long a client that misbehaved is refused authority over the same object.
+const REVOKE_COOLDOWN_SECS: f64 = 5.;
+
+/// Which peer simulates the rigid body of a `NetObject`. The server simulates every object
+/// unless it hands authority to the client holding or touching it.
+#[derive(Component, Default)]
+pub struct Authority {
+    /// The player simulating the object, `None` when the server does.
+    pub owner: Option<PlayerId>,
+    /// When authority gained by touching the object runs out, in seconds since startup.
+    touch_expires: f64,
+    /// When the owner's last state was accepted, in seconds since startup.
+    last_accepted: f64,
+    /// Rejected states since the current owner got authority.
+    strikes: u32,
+    /// Velocities last reported by the owner, handed to the server's body when it takes over.
+    linvel: Vec3,
+    angvel: Vec3,
+    /// A player that misbehaved and when it may get authority again.
+    revoked: Option<(PlayerId, f64)>,
+}
+
+/// Hands simulation of `NetObject`s to the clients interacting with them
+pub struct AuthorityPlugin {
+    pub mode: NetworkMode,
+}
+
+impl Plugin for AuthorityPlugin {
+    fn build(&self, app: &mut App) {
+        match self.mode {
+            NetworkMode::Offline => {}
+            NetworkMode::Host(_) => {
+                app.add_system(init_server_objects)
+                    .add_system(accept_object_states.before(assign_authority))
+                    .add_system(
+                        assign_authority
+                            .after(MultiplayerSystem::Grab)
+                            .after(PlayerSystem::Move),
+                    )
+                    .add_system(
+                        replicate_objects
+                            .after(MultiplayerSystem::Replicate)
+                            .after(assign_authority),
+                    );
+            }
+            NetworkMode::Client(_) => {
+                app.add_system(init_client_objects)
+                    .add_system(client_object_handler)
+                    .add_system(
+                        sync_client_body_types
+                            .after(client_object_handler)
+                            .after(MultiplayerSystem::Grab),
+                    )
+                    .add_system(send_owned_object_states);
+            }
+        }
+    }
+}
+
+fn init_server_objects(mut commands: Commands, objects: Query<Entity, Added<NetObject>>) {
+    for entity in objects.iter() {
+        commands.entity(entity).insert(Authority::default());
+    }
+}
+
+/// Clients only simulate the objects they have authority over, everything else follows the
+/// server
+fn init_client_objects(
+    mut commands: Commands,
+    mut objects: Query<(Entity, &mut RigidBody), Added<NetObject>>,
+) {
+    for (entity, mut body) in objects.iter_mut() {
+        *body = RigidBody::KinematicPositionBased;
+        commands.entity(entity).insert(Authority::default());
+    }
+}
+
+/// Gives a client authority over the object it holds or touches, and takes it back once it
+/// lets go, stops touching it or had authority revoked
+fn assign_authority(
+    time: Res<Time>,
+    net: Res<NetworkResource>,
+    mut transport: ResMut<Transport>,
+    remote_bodies: Query<
+        (Entity, &NetPlayer, &CharacterController),
+        (With<FPSBody>, Without<LocalPlayer>),
+    >,
+    mut objects: Query<(
+        Entity,
+        &NetObject,
+        &Grabbable,
+        &mut Authority,
+        &mut RigidBody,
+        &mut Velocity,
+    )>,
+) {
+    let now = time.elapsed_seconds_f64();
+
+    for (entity, object, grabbable, mut authority, mut body, mut velocity) in objects.iter_mut() {
+        let holder = grabbable
+            .holder
+            .and_then(|h| remote_bodies.get(h).ok())
+            .map(|(_, player, _)| player.0);
+        let toucher = remote_bodies
+            .iter()
+            .find(|(_, _, controller)| controller.touching.contains(&entity))
+            .map(|(_, player, _)| player.0);
+
+        if holder.is_none() && toucher.is_some() {
+            authority.touch_expires = now + TOUCH_AUTHORITY_SECS;
+        }
+
+        let mut owner = holder.or(toucher);
+        if owner.is_none() && grabbable.holder.is_none() && now < authority.touch_expires {
+            owner = authority.owner;
+        }
+        if let Some((player, until)) = authority.revoked {
+            if owner == Some(player) && now < until {
+                owner = None;
+            }
+        }
+
+        if owner != authority.owner {
+            set_owner(&mut authority, &mut body, &mut velocity, owner, now);
+            transport.broadcast(
+                net.connections.keys(),
+                Message::ObjectAuthority {
+                    object: object.0,
+                    owner,
+                },
+            );
+        }
+    }
+}
+
+fn set_owner(
+    authority: &mut Authority,
+    body: &mut RigidBody,
+    velocity: &mut Velocity,
+    owner: Option<PlayerId>,
+    now: f64,
+) {
+    match owner {
+        Some(_) => {
+            *body = RigidBody::KinematicPositionBased;
+        }
+        None => {
+            // carry on from where the client left the object
+            *body = RigidBody::Dynamic;
+            velocity.linvel = authority.linvel;
+            velocity.angvel = authority.angvel;
+        }
+    }
+    authority.owner = owner;
+    authority.strikes = 0;
+    authority.last_accepted = now;
+}
+
+/// Applies object states sent by the client with authority over them, as long as they are
+/// plausible
+fn accept_object_states(
+    time: Res<Time>,
+    mut events: EventReader<NetworkEvent>,
+    players: Res<NetPlayers>,
+    net: Res<NetworkResource>,
+    mut transport: ResMut<Transport>,
+    bodies: Query<&Transform, (With<FPSBody>, Without<NetObject>)>,
+    mut objects: Query<(
+        &NetObject,
+        &mut Transform,
+        &mut Authority,
+        &mut RigidBody,
+        &mut Velocity,
+    )>,
+) {
+    let now = time.elapsed_seconds_f64();
+
+    for event in events.iter() {
+        let (addr, object, translation, rotation, linvel, angvel) = match event {
+            NetworkEvent::Message(
+                addr,
+                Message::ObjectState {
+                    object,
+                    translation,
+                    rotation,
+                    linvel,
+                    angvel,
+                },
+            ) => (addr, object, translation, rotation, linvel, angvel),
+            _ => continue,
+        };
+        let id = match players.addresses.get(addr) {
+            Some(id) => *id,
+            None => continue,
+        };
+        let (_, mut transform, mut authority, mut body, mut velocity) = match objects
+            .iter_mut()
+            .find(|(o, _, a, ..)| o.0 == *object && a.owner == Some(id))
+        {
+            Some(object) => object,
+            // authority moved on since the client sent this
+            None => continue,
+        };
+
+        let elapsed = (now - authority.last_accepted).min(1.) as f32;
+        let owner_position = players
+            .entities
+            .get(&id)
+            .and_then(|body| bodies.get(*body).ok())
+            .map(|body| body.translation);
+        let plausible = translation.is_finite()
+            && rotation.is_finite()
+            && (rotation.length_squared() - 1.).abs() <= ROTATION_SLACK
+            && linvel.is_finite()
+            && angvel.is_finite()
+            && linvel.length() <= MAX_OBJECT_SPEED
+            && owner_position.map_or(false, |p| p.distance(*translation) <= MAX_OWNER_DISTANCE)
+            && transform.translation.distance(*translation)
+                <= MAX_OBJECT_SPEED * elapsed + POSITION_SLACK;
+
+        if plausible {
+            transform.translation = *translation;
+            transform.rotation = rotation.normalize();
+            authority.linvel = *linvel;
+            authority.angvel = *angvel;
+            authority.last_accepted = now;
+            continue;
+        }
+
+        authority.strikes += 1;
+        warn!(
+            "{}: rejected state for object {} ({} strikes)",
+            addr, object, authority.strikes
+        );
+        if authority.strikes >= MAX_STRIKES {
+            warn!("{}: revoking authority over object {}", addr, object);
+            authority.revoked = Some((id, now + REVOKE_COOLDOWN_SECS));
+            // don't carry over velocities from a client that misbehaved
+            authority.linvel = Vec3::ZERO;
+            authority.angvel = Vec3::ZERO;
+            set_owner(&mut authority, &mut body, &mut velocity, None, now);
+            transport.broadcast(
+                net.connections.keys(),
+                Message::ObjectAuthority {
+                    object: *object,
+                    owner: None,
+                },
+            );
+        }
+    }
+}
+
+/// Sends the state of every object to all clients but the one simulating it
+fn replicate_objects(
+    network_tick: Res<NetworkTick>,
+    players: Res<NetPlayers>,
+    net: Res<NetworkResource>,
+    mut transport: ResMut<Transport>,
+    objects: Query<(&NetObject, &Transform, &Velocity, &Authority)>,
+) {
+    if !network_tick.just_ticked() {
+        return;
+    }
+
+    for (object, transform, velocity, authority) in objects.iter() {
+        let owner_addr = authority.owner.and_then(|id| players.address_of(id));
+        let (linvel, angvel) = match authority.owner {
+            Some(_) => (authority.linvel, authority.angvel),
+            None => (velocity.linvel, velocity.angvel),
+        };
+
+        for addr in net.connections.keys() {
+            if Some(*addr) == owner_addr {
+                continue;
+            }
+            transport.send_to(
+                *addr,
+                Message::ObjectState {
+                    object: object.0,
+                    translation: transform.translation,
+                    rotation: transform.rotation,
+                    linvel,
+                    angvel,
+                },
+            );
+        }
+        // repeated in case a change of authority was lost
+        transport.broadcast(
+            net.connections.keys(),
+            Message::ObjectAuthority {
+                object: object.0,
+                owner: authority.owner,
+            },
+        );
+    }
+}
+
+fn client_object_handler(
+    mut events: EventReader<NetworkEvent>,
+    players: Res<NetPlayers>,
+    state: Res<PlayerState>,
+    mut objects: Query<(
+        Entity,
+        &NetObject,
+        &mut Transform,
+        &mut Velocity,
+        &mut Authority,
+    )>,
+) {
+    for event in events.iter() {
+        match event {
+            NetworkEvent::Message(_, Message::ObjectAuthority { object, owner }) => {
+                if let Some((.., mut authority)) =
+                    objects.iter_mut().find(|(_, o, ..)| o.0 == *object)
+                {
+                    authority.owner = *owner;
+                }
+            }
+            NetworkEvent::Message(
+                _,
+                Message::ObjectState {
+                    object,
+                    translation,
+                    rotation,
+                    linvel,
+                    angvel,
+                },
+            ) => {
+                if let Some((entity, _, mut transform, mut velocity, authority)) =
+                    objects.iter_mut().find(|(_, o, ..)| o.0 == *object)
+                {
+                    // objects simulated here are the source of truth
+                    if authority.owner.is_some() && authority.owner == players.local
+                        || state.grabbing == Some(entity)
+                    {
+                        continue;
+                    }
+                    transform.translation = *translation;
+                    transform.rotation = *rotation;
+                    velocity.linvel = *linvel;
+                    velocity.angvel = *angvel;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Simulates the objects this client has authority over, or is optimistically holding, and
+/// moves the rest kinematically
+fn sync_client_body_types(
+    players: Res<NetPlayers>,
+    state: Res<PlayerState>,
+    mut objects: Query<(Entity, &Authority, &mut RigidBody), With<NetObject>>,
+) {
+    for (entity, authority, mut body) in objects.iter_mut() {
+        let simulated = authority.owner.is_some() && authority.owner == players.local
+            || state.grabbing == Some(entity);
+        let wanted = if simulated {
+            RigidBody::Dynamic
+        } else {
+            RigidBody::KinematicPositionBased
+        };
+        if *body != wanted {
+            *body = wanted;
+        }
+    }
+}
+
+/// Sends the state of the objects this client has authority over to the server
+fn send_owned_object_states(
+    network_tick: Res<NetworkTick>,
+    players: Res<NetPlayers>,
+    mut transport: ResMut<Transport>,
+    objects: Query<(&NetObject, &Transform, &Velocity, &Authority)>,
+) {
+    if !network_tick.just_ticked() {
+        return;
+    }
+
+    for (object, transform, velocity, authority) in objects.iter() {
+        if authority.owner.is_none() || authority.owner != players.local {
+            continue;
+        }
+        transport.send(Message::ObjectState {
+            object: object.0,
+            translation: transform.translation,
+            rotation: transform.rotation,
+            linvel: velocity.linvel,
+            angvel: velocity.angvel,
+        });
+    }
+}
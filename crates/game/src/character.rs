@@ -0,0 +1,366 @@
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::player::MovementSettings;
+
+/// Gap kept between a character and whatever it runs into, so the next cast doesn't start out
+/// touching it.
+const SKIN: f32 = 0.02;
+/// How many times a single move may slide along something it hit.
+const MAX_SLIDES: usize = 4;
+/// How far below its feet a character still counts as standing on the ground.
+const GROUND_PROBE: f32 = SKIN * 2.;
+/// Shortest move worth casting for.
+const MIN_MOVE: f32 = 1e-4;
+
+/// Moves a kinematic body by casting its collider through the world instead of leaving it to
+/// the physics solver, see `move_character`.
+#[derive(Component, Default)]
+pub struct CharacterController {
+    /// Velocity the body moves with, including falling.
+    pub velocity: Vec3,
+    /// Everything the last move ran into, the ground included.
+    pub touching: Vec<Entity>,
+}
+
+/// Something a character ran into while moving.
+pub struct Hit {
+    pub entity: Entity,
+    /// Normal of the surface that was hit, pointing towards the character.
+    pub normal: Vec3,
+    /// Whether the surface is flat enough to walk on.
+    pub walkable: bool,
+}
+
+/// Where a character ended up after a move.
+pub struct Movement {
+    pub translation: Vec3,
+    /// Whether it stands on ground flat enough to walk on.
+    pub grounded: bool,
+    pub hits: Vec<Hit>,
+}
+
+/// Moves the character `entity` with collider `shape` from `translation` by `motion`, sliding
+/// along whatever it runs into. A `grounded` character, one that stands on the ground and isn't
+/// jumping off it, treats slopes steeper than the `MovementSettings` allow as walls, steps up
+/// onto ledges and follows the ground down slopes and steps instead of flying off them.
+pub fn move_character(
+    rapier_context: &RapierContext,
+    entity: Entity,
+    shape: &Collider,
+    translation: Vec3,
+    motion: Vec3,
+    grounded: bool,
+    settings: &MovementSettings,
+) -> Movement {
+    let caster = Caster {
+        rapier_context,
+        shape,
+        filter: QueryFilter::new()
+            .exclude_rigid_body(entity)
+            .exclude_sensors(),
+        min_ground_normal: settings.max_slope_angle.to_radians().cos(),
+    };
+    let mut position = translation;
+    let mut remaining = motion;
+    let mut hits = Vec::new();
+
+    for _ in 0..MAX_SLIDES {
+        let distance = remaining.length();
+        if distance < MIN_MOVE {
+            break;
+        }
+        let direction = remaining / distance;
+        let (entity, free, normal) = match caster.cast(position, direction, distance) {
+            Some(hit) => hit,
+            None => {
+                position += remaining;
+                break;
+            }
+        };
+        if free == 0. && direction.dot(normal) >= 0. {
+            // starting out inside something, don't let it hold us back from leaving it
+            position += remaining;
+            break;
+        }
+
+        position += direction * free;
+        remaining -= direction * free;
+        let walkable = caster.is_ground(normal);
+        hits.push(Hit {
+            entity,
+            normal,
+            walkable,
+        });
+
+        if grounded && !walkable {
+            let horizontal = Vec3::new(remaining.x, 0., remaining.z);
+            if let Some((stepped, travelled)) =
+                caster.step_up(position, horizontal, settings.step_height)
+            {
+                position = stepped;
+                remaining -= horizontal.normalize() * travelled;
+                continue;
+            }
+        }
+
+        // a character on the ground can't climb a slope that is too steep by walking into it
+        let normal = if grounded && !walkable && normal.y > 0. {
+            Vec3::new(normal.x, 0., normal.z).normalize_or_zero()
+        } else {
+            normal
+        };
+        remaining -= normal * remaining.dot(normal);
+    }
+
+    let probe = if grounded {
+        settings.snap_distance.max(GROUND_PROBE)
+    } else {
+        GROUND_PROBE
+    };
+    let mut on_ground = false;
+    if let Some((entity, free, normal)) = caster.cast(position, Vec3::NEG_Y, probe) {
+        if caster.is_ground(normal) {
+            on_ground = true;
+            if grounded {
+                position.y -= free;
+            }
+            hits.push(Hit {
+                entity,
+                normal,
+                walkable: true,
+            });
+        }
+    }
+
+    Movement {
+        translation: position,
+        grounded: on_ground,
+        hits,
+    }
+}
+
+struct Caster<'a> {
+    rapier_context: &'a RapierContext,
+    shape: &'a Collider,
+    filter: QueryFilter<'a>,
+    /// Smallest `y` of the normal of a surface that can be stood on.
+    min_ground_normal: f32,
+}
+
+impl Caster<'_> {
+    /// Casts the shape from `from` along `direction`, a unit vector, for up to `distance`.
+    /// Returns what it hit, how far it can go before getting closer than `SKIN` and the normal
+    /// of the surface it hit.
+    fn cast(&self, from: Vec3, direction: Vec3, distance: f32) -> Option<(Entity, f32, Vec3)> {
+        let (entity, toi) = self.rapier_context.cast_shape(
+            from,
+            Quat::IDENTITY,
+            direction,
+            self.shape,
+            distance + SKIN,
+            self.filter,
+        )?;
+        // the shape isn't rotated, so its local normal is the world one
+        let normal = -toi.normal1;
+        if !normal.is_normalized() {
+            return None;
+        }
+        Some((entity, (toi.toi - SKIN).clamp(0., distance), normal))
+    }
+
+    fn is_ground(&self, normal: Vec3) -> bool {
+        normal.y >= self.min_ground_normal
+    }
+
+    /// Tries to climb a ledge up to `height` tall while moving by `horizontal`: up, forward and
+    /// back down onto the ledge. Returns where that ends up and how far forward it got, as long
+    /// as it lands on walkable ground.
+    fn step_up(&self, from: Vec3, horizontal: Vec3, height: f32) -> Option<(Vec3, f32)> {
+        let distance = horizontal.length();
+        if height <= 0. || distance < MIN_MOVE {
+            return None;
+        }
+        let direction = horizontal / distance;
+
+        let raised = match self.cast(from, Vec3::Y, height) {
+            Some((_, free, _)) => free,
+            None => height,
+        };
+        let above = from + Vec3::Y * raised;
+        let travelled = match self.cast(above, direction, distance) {
+            Some((_, free, _)) => free,
+            None => distance,
+        };
+        if travelled < MIN_MOVE {
+            return None;
+        }
+        let forward = above + direction * travelled;
+
+        let (_, drop, normal) = self.cast(forward, Vec3::NEG_Y, raised)?;
+        if !self.is_ground(normal) {
+            return None;
+        }
+        Some((forward - Vec3::Y * drop, travelled))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_rapier3d::rapier::geometry::ColliderBuilder;
+    use bevy_rapier3d::rapier::math::{Isometry, Vector};
+
+    use super::*;
+
+    const BODY: Entity = Entity::from_raw(0);
+    const FLOOR: Entity = Entity::from_raw(1);
+    const OBSTACLE: Entity = Entity::from_raw(2);
+    /// Where the center of a player capsule standing on the floor is.
+    const STANDING: f32 = 1.5 + SKIN;
+
+    fn player() -> Collider {
+        Collider::capsule_y(1., 0.5)
+    }
+
+    /// A floor with its top at y = 0, along with cuboids of the given half extents, positions
+    /// and rotations around the z axis.
+    fn scene(obstacles: &[(Vec3, Vec3, f32)]) -> RapierContext {
+        let mut context = RapierContext::default();
+        let mut add = |entity: Entity, half_extents: Vec3, position: Vec3, angle: f32| {
+            let collider = ColliderBuilder::cuboid(half_extents.x, half_extents.y, half_extents.z)
+                .position(Isometry::new(
+                    Vector::new(position.x, position.y, position.z),
+                    Vector::z() * angle,
+                ))
+                .user_data(entity.to_bits() as u128)
+                .build();
+            context.colliders.insert(collider);
+        };
+        add(FLOOR, Vec3::new(20., 0.5, 20.), Vec3::new(0., -0.5, 0.), 0.);
+        for (half_extents, position, angle) in obstacles {
+            add(OBSTACLE, *half_extents, *position, *angle);
+        }
+        context
+            .query_pipeline
+            .update(&context.bodies, &context.colliders);
+        context
+    }
+
+    /// A ramp rising towards +x at `degrees`, starting from the floor at x = 1.
+    fn ramp(degrees: f32) -> (Vec3, Vec3, f32) {
+        let angle = degrees.to_radians();
+        let (sin, cos) = angle.sin_cos();
+        let half_extents = Vec3::new(5., 0.5, 5.);
+        let top_middle = Vec3::new(1. + 5. * cos, 5. * sin, 0.);
+        let normal = Vec3::new(-sin, cos, 0.);
+        (half_extents, top_middle - normal * half_extents.y, angle)
+    }
+
+    /// Walks a grounded player from standing at the origin by `step` every frame.
+    fn walk(context: &RapierContext, step: Vec3, frames: usize) -> Movement {
+        let settings = MovementSettings::default();
+        let mut movement = Movement {
+            translation: Vec3::Y * STANDING,
+            grounded: true,
+            hits: Vec::new(),
+        };
+        for _ in 0..frames {
+            movement = move_character(
+                context,
+                BODY,
+                &player(),
+                movement.translation,
+                step,
+                movement.grounded,
+                &settings,
+            );
+        }
+        movement
+    }
+
+    #[test]
+    fn test_walks_up_gentle_slope() {
+        let context = scene(&[ramp(20.)]);
+        let movement = walk(&context, Vec3::X * 0.1, 30);
+        assert!(movement.grounded);
+        assert!(movement.translation.x > 2., "{:?}", movement.translation);
+        assert!(
+            movement.translation.y > STANDING + 0.3,
+            "{:?}",
+            movement.translation
+        );
+    }
+
+    #[test]
+    fn test_steep_slope_blocks() {
+        let context = scene(&[ramp(60.)]);
+        let movement = walk(&context, Vec3::X * 0.1, 30);
+        assert!(movement.grounded);
+        assert!(movement.translation.x < 1., "{:?}", movement.translation);
+        assert!(
+            (movement.translation.y - STANDING).abs() < 0.05,
+            "{:?}",
+            movement.translation
+        );
+    }
+
+    #[test]
+    fn test_steps_up_low_ledge() {
+        let ledge = (Vec3::new(1.5, 0.1, 5.), Vec3::new(2.5, 0.1, 0.), 0.);
+        let context = scene(&[ledge]);
+        let movement = walk(&context, Vec3::X * 0.1, 20);
+        assert!(movement.grounded);
+        assert!(movement.translation.x > 1.4, "{:?}", movement.translation);
+        assert!(
+            (movement.translation.y - (STANDING + 0.2)).abs() < 0.05,
+            "{:?}",
+            movement.translation
+        );
+    }
+
+    #[test]
+    fn test_tall_ledge_blocks() {
+        let ledge = (Vec3::new(1.5, 0.25, 5.), Vec3::new(2.5, 0.25, 0.), 0.);
+        let context = scene(&[ledge]);
+        let movement = walk(&context, Vec3::X * 0.1, 15);
+        assert!(movement.translation.x < 0.5, "{:?}", movement.translation);
+        assert!(
+            (movement.translation.y - STANDING).abs() < 0.05,
+            "{:?}",
+            movement.translation
+        );
+    }
+
+    #[test]
+    fn test_snaps_to_ground_only_when_grounded() {
+        let context = scene(&[]);
+        let settings = MovementSettings::default();
+        let hovering = Vec3::Y * (STANDING + 0.2);
+
+        let snapped = move_character(
+            &context,
+            BODY,
+            &player(),
+            hovering,
+            Vec3::ZERO,
+            true,
+            &settings,
+        );
+        assert!(snapped.grounded);
+        assert!((snapped.translation.y - STANDING).abs() < 0.01);
+        assert!(snapped.hits.iter().any(|hit| hit.entity == FLOOR));
+
+        // jumping or falling, it is left in the air
+        let airborne = move_character(
+            &context,
+            BODY,
+            &player(),
+            hovering,
+            Vec3::ZERO,
+            false,
+            &settings,
+        );
+        assert!(!airborne.grounded);
+        assert_eq!(airborne.translation, hovering);
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_egui::{egui, EguiContext};
+use net::{Message, NetworkEvent, NetworkResource, PlayerId, Transport};
+
+use crate::network::*;
+use crate::player::*;
+
+/// Longest line of chat in bytes, so a relayed line still fits in a single datagram.
+const MAX_CHAT_LEN: usize = 200;
+/// How many lines the chat box keeps around.
+const MAX_CHAT_LINES: usize = 50;
+/// How many of the latest lines the chat box shows.
+const VISIBLE_CHAT_LINES: usize = 10;
+/// How many lines a player may send in quick succession.
+const CHAT_BURST: f32 = 5.;
+/// How many lines per second a player may keep sending after a burst.
+const CHAT_RATE: f32 = 1.;
+/// Who the server's own lines are from.
+const SERVER_NAME: &str = "server";
+
+/// A line of chat as the server relayed it.
+pub struct ChatLine {
+    pub sender: String,
+    /// When the server relayed the line, in seconds since it started.
+    pub time: u64,
+    pub text: String,
+}
+
+/// The chat box: the latest lines of chat and the line being typed.
+#[derive(Resource, Default)]
+pub struct Chat {
+    pub lines: VecDeque<ChatLine>,
+    /// Whether the chat box is open and takes the keyboard.
+    pub typing: bool,
+    draft: String,
+}
+
+impl Chat {
+    pub fn push(&mut self, line: ChatLine) {
+        self.lines.push_back(line);
+        while self.lines.len() > MAX_CHAT_LINES {
+            self.lines.pop_front();
+        }
+    }
+}
+
+/// Sent when the local player submits a line in the chat box.
+pub struct ChatSubmitted(pub String);
+
+/// Lets a player send `CHAT_BURST` lines at once, refilled at `CHAT_RATE` lines per second.
+struct RateLimit {
+    allowance: f32,
+    last: f64,
+}
+
+/// The server's rate limit for every player that has chatted.
+#[derive(Resource, Default)]
+struct ChatLimits(HashMap<PlayerId, RateLimit>);
+
+impl ChatLimits {
+    fn allow(&mut self, player: PlayerId, now: f64) -> bool {
+        let limit = self.0.entry(player).or_insert(RateLimit {
+            allowance: CHAT_BURST,
+            last: now,
+        });
+        let refill = (now - limit.last) as f32 * CHAT_RATE;
+        limit.allowance = (limit.allowance + refill).min(CHAT_BURST);
+        limit.last = now;
+
+        if limit.allowance < 1. {
+            return false;
+        }
+        limit.allowance -= 1.;
+        true
+    }
+}
+
+/// A text chat between every peer. The server relays lines with the sender's name and the time
+/// and answers `/` commands itself.
+pub struct ChatPlugin {
+    pub mode: NetworkMode,
+}
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Chat>()
+            .add_event::<ChatSubmitted>()
+            .add_system(chat_box.before(PlayerSystem::Input));
+
+        match self.mode {
+            NetworkMode::Offline => {
+                app.add_system(echo_chat);
+            }
+            NetworkMode::Host(_) => {
+                app.init_resource::<ChatLimits>()
+                    .add_system(server_chat_handler);
+            }
+            NetworkMode::Client(_) => {
+                app.add_system(send_chat).add_system(client_chat_handler);
+            }
+        }
+    }
+}
+
+/// Trims a line, drops control characters and cuts it to `MAX_CHAT_LEN`.
+fn sanitize(text: &str) -> String {
+    let mut clean = String::new();
+    for c in text.trim().chars().filter(|c| !c.is_control()) {
+        if clean.len() + c.len_utf8() > MAX_CHAT_LEN {
+            break;
+        }
+        clean.push(c);
+    }
+    clean
+}
+
+fn player_name(players: &NetPlayers, id: PlayerId) -> String {
+    if players.local == Some(id) {
+        "host".to_string()
+    } else {
+        format!("player {}", id)
+    }
+}
+
+/// Draws the latest lines of chat. Enter opens the box and sends the line typed into it, Escape
+/// closes it. Player input is suspended while it is open.
+fn chat_box(
+    mut egui_context: ResMut<EguiContext>,
+    mut keys: ResMut<Input<KeyCode>>,
+    mut chat: ResMut<Chat>,
+    mut suspended: ResMut<InputSuspended>,
+    mut submitted: EventWriter<ChatSubmitted>,
+) {
+    let chat = &mut *chat;
+    let was_typing = chat.typing;
+    if !chat.typing && keys.clear_just_pressed(KeyCode::Return) {
+        chat.typing = true;
+    } else if chat.typing && keys.clear_just_pressed(KeyCode::Escape) {
+        chat.typing = false;
+        chat.draft.clear();
+    }
+
+    egui::Area::new("chat")
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(8., -8.))
+        .show(egui_context.ctx_mut(), |ui| {
+            egui::Frame::none()
+                .fill(egui::Color32::from_black_alpha(96))
+                .inner_margin(egui::style::Margin::same(6.))
+                .show(ui, |ui| {
+                    let skip = chat.lines.len().saturating_sub(VISIBLE_CHAT_LINES);
+                    for line in chat.lines.iter().skip(skip) {
+                        ui.label(format!(
+                            "[{}:{:02}] {}: {}",
+                            line.time / 60,
+                            line.time % 60,
+                            line.sender,
+                            line.text
+                        ));
+                    }
+
+                    if chat.typing {
+                        let response = ui.add(
+                            egui::TextEdit::singleline(&mut chat.draft)
+                                .hint_text("say something, or /help")
+                                .desired_width(400.),
+                        );
+                        if response.lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+                            chat.typing = false;
+                            let text = std::mem::take(&mut chat.draft);
+                            if !text.trim().is_empty() {
+                                submitted.send(ChatSubmitted(text));
+                            }
+                        } else {
+                            response.request_focus();
+                        }
+                    }
+                });
+        });
+
+    // the key that closed the box shouldn't reach the player systems either
+    suspended.set("chat", chat.typing || was_typing);
+}
+
+/// Shows the local player's lines in single player, where there is no one to relay them
+fn echo_chat(time: Res<Time>, mut submitted: EventReader<ChatSubmitted>, mut chat: ResMut<Chat>) {
+    for ChatSubmitted(text) in submitted.iter() {
+        chat.push(ChatLine {
+            sender: "you".to_string(),
+            time: time.elapsed().as_secs(),
+            text: sanitize(text),
+        });
+    }
+}
+
+fn send_chat(mut submitted: EventReader<ChatSubmitted>, mut transport: ResMut<Transport>) {
+    for ChatSubmitted(text) in submitted.iter() {
+        transport.send_reliable(Message::Chat {
+            sender: String::new(),
+            time: 0,
+            text: sanitize(text),
+        });
+    }
+}
+
+fn client_chat_handler(mut events: EventReader<NetworkEvent>, mut chat: ResMut<Chat>) {
+    for event in events.iter() {
+        if let NetworkEvent::Message(_, Message::Chat { sender, time, text }) = event {
+            chat.push(ChatLine {
+                sender: sender.clone(),
+                time: *time,
+                text: text.clone(),
+            });
+        }
+    }
+}
+
+/// Relays the lines of clients and the host, or runs them as commands
+fn server_chat_handler(
+    time: Res<Time>,
+    mut events: EventReader<NetworkEvent>,
+    mut submitted: EventReader<ChatSubmitted>,
+    players: Res<NetPlayers>,
+    mut net: ResMut<NetworkResource>,
+    mut transport: ResMut<Transport>,
+    mut limits: ResMut<ChatLimits>,
+    mut chat: ResMut<Chat>,
+) {
+    let mut server = ChatServer {
+        now: time.elapsed_seconds_f64(),
+        players: &players,
+        net: &mut net,
+        transport: &mut transport,
+        limits: &mut limits,
+        chat: &mut chat,
+    };
+
+    for event in events.iter() {
+        if let NetworkEvent::Message(addr, Message::Chat { text, .. }) = event {
+            if let Some(id) = players.addresses.get(addr) {
+                server.handle(*id, text);
+            }
+        }
+    }
+    if let Some(host) = players.local {
+        for ChatSubmitted(text) in submitted.iter() {
+            server.handle(host, text);
+        }
+    }
+}
+
+/// Everything the server needs to handle a line of chat.
+struct ChatServer<'a> {
+    now: f64,
+    players: &'a NetPlayers,
+    net: &'a mut NetworkResource,
+    transport: &'a mut Transport,
+    limits: &'a mut ChatLimits,
+    chat: &'a mut Chat,
+}
+
+impl ChatServer<'_> {
+    fn handle(&mut self, sender: PlayerId, text: &str) {
+        let text = sanitize(text);
+        if text.is_empty() {
+            return;
+        }
+        let is_host = self.players.local == Some(sender);
+        if !is_host && !self.limits.allow(sender, self.now) {
+            self.reply(sender, "you are sending messages too fast".to_string());
+            return;
+        }
+
+        match text.strip_prefix('/') {
+            Some(command) => self.command(sender, is_host, command),
+            None => {
+                let name = player_name(self.players, sender);
+                self.broadcast(name, text);
+            }
+        }
+    }
+
+    fn command(&mut self, sender: PlayerId, is_host: bool, command: &str) {
+        let mut args = command.split_whitespace();
+        match args.next() {
+            Some("who") => {
+                let mut ids: Vec<_> = self.players.local.into_iter().collect();
+                ids.extend(self.players.addresses.values());
+                let names: Vec<_> = ids
+                    .into_iter()
+                    .map(|id| player_name(self.players, id))
+                    .collect();
+                self.reply(
+                    sender,
+                    format!("{} connected: {}", names.len(), names.join(", ")),
+                );
+            }
+            Some("kick") if !is_host => {
+                self.reply(sender, "only the host can kick players".to_string());
+            }
+            Some("kick") => {
+                let target = match args.next().and_then(|id| id.parse().ok()) {
+                    Some(target) => target,
+                    None => {
+                        self.reply(sender, "usage: /kick <player id> [reason]".to_string());
+                        return;
+                    }
+                };
+                let addr = match self.players.address_of(target) {
+                    Some(addr) => addr,
+                    None => {
+                        self.reply(sender, format!("there is no player {} to kick", target));
+                        return;
+                    }
+                };
+                let reason = args.collect::<Vec<_>>().join(" ");
+                let reason = if reason.is_empty() {
+                    "kicked by the host".to_string()
+                } else {
+                    reason
+                };
+                info!("kicking player {}: {}", target, reason);
+                self.net.kick(self.transport, addr, reason);
+                self.broadcast(
+                    SERVER_NAME.to_string(),
+                    format!("{} was kicked", player_name(self.players, target)),
+                );
+            }
+            Some("help") | None => {
+                self.reply(
+                    sender,
+                    "commands: /who, /kick <player id> [reason] (host only)".to_string(),
+                );
+            }
+            Some(unknown) => {
+                self.reply(sender, format!("unknown command /{}, try /help", unknown));
+            }
+        }
+    }
+
+    /// Sends a line to everyone, the host included.
+    fn broadcast(&mut self, sender: String, text: String) {
+        let time = self.now as u64;
+        self.transport.broadcast_reliable(
+            self.net.connections.keys(),
+            Message::Chat {
+                sender: sender.clone(),
+                time,
+                text: text.clone(),
+            },
+        );
+        self.chat.push(ChatLine { sender, time, text });
+    }
+
+    /// Answers a single player on behalf of the server.
+    fn reply(&mut self, to: PlayerId, text: String) {
+        let time = self.now as u64;
+        if self.players.local == Some(to) {
+            self.chat.push(ChatLine {
+                sender: SERVER_NAME.to_string(),
+                time,
+                text,
+            });
+        } else if let Some(addr) = self.players.address_of(to) {
+            self.transport.send_reliable_to(
+                addr,
+                Message::Chat {
+                    sender: SERVER_NAME.to_string(),
+                    time,
+                    text,
+                },
+            );
+        }
+    }
+}
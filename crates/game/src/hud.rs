@@ -0,0 +1,169 @@
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use bevy_rapier3d::prelude::*;
+
+use crate::actions::*;
+use crate::interact::*;
+use crate::menu::GameState;
+use crate::player::*;
+
+/// Light added to the materials of whatever the reticle is on.
+const HIGHLIGHT: Color = Color::rgb(0.25, 0.25, 0.15);
+
+/// Marks a mesh of something highlighted, whose material was swapped for a brighter copy of the
+/// one kept here.
+#[derive(Component)]
+struct Highlighted(Handle<StandardMaterial>);
+
+/// A crosshair in the middle of the screen, which highlights the `Focus` and says what can be
+/// done with it
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(highlight.after(InteractSystem::Focus))
+            .add_system(reticle.after(InteractSystem::Focus));
+    }
+}
+
+/// Tints the focus by swapping the materials of its meshes for brighter copies, and swaps them
+/// back once it isn't focused anymore
+fn highlight(
+    mut commands: Commands,
+    focus: Res<Focus>,
+    mut highlighted: Local<Option<Entity>>,
+    children: Query<&Children>,
+    mut meshes: Query<(&mut Handle<StandardMaterial>, Option<&Highlighted>)>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    if *highlighted == focus.entity {
+        return;
+    }
+
+    if let Some(previous) = highlighted.take() {
+        for entity in descendants(previous, &children) {
+            if let Ok((mut material, Some(original))) = meshes.get_mut(entity) {
+                *material = original.0.clone();
+                commands.entity(entity).remove::<Highlighted>();
+            }
+        }
+    }
+    if let Some(focused) = focus.entity {
+        for entity in descendants(focused, &children) {
+            if let Ok((mut material, None)) = meshes.get_mut(entity) {
+                let mut tinted = match materials.get(&*material) {
+                    Some(tinted) => tinted.clone(),
+                    None => continue,
+                };
+                tinted.emissive = tinted.emissive + HIGHLIGHT;
+                commands
+                    .entity(entity)
+                    .insert(Highlighted(material.clone()));
+                *material = materials.add(tinted);
+            }
+        }
+    }
+    *highlighted = focus.entity;
+}
+
+/// `root` and everything below it in the hierarchy, such as the meshes of a scene.
+fn descendants(root: Entity, children: &Query<&Children>) -> Vec<Entity> {
+    let mut found = vec![root];
+    let mut next = 0;
+    while next < found.len() {
+        if let Ok(below) = children.get(found[next]) {
+            found.extend(below.iter().copied());
+        }
+        next += 1;
+    }
+    found
+}
+
+/// Draws the crosshair, which shows whether something can be picked up, used or is being held,
+/// along with prompts saying what the grab and interact actions would do to it. Holding down the
+/// interact action fills a ring around it.
+fn reticle(
+    mut egui_context: ResMut<EguiContext>,
+    game: Res<State<GameState>>,
+    focus: Res<Focus>,
+    progress: Res<InteractProgress>,
+    state: Res<PlayerState>,
+    bindings: Res<InputBindings>,
+    grabbables: Query<(&Grabbable, &ReadMassProperties)>,
+    interactables: Query<&Interactable>,
+    names: Query<&Name>,
+) {
+    if *game.current() != GameState::Playing {
+        return;
+    }
+
+    let ctx = egui_context.ctx_mut();
+    let center = ctx.screen_rect().center();
+    let painter = ctx.layer_painter(egui::LayerId::new(
+        egui::Order::Foreground,
+        egui::Id::new("reticle"),
+    ));
+    let color = egui::Color32::from_white_alpha(200);
+    let name = |entity| names.get(entity).map_or("it", |name: &Name| name.as_str());
+
+    let mut prompts = Vec::new();
+    if let Some(held) = state.grabbing {
+        painter.circle_stroke(center, 10., egui::Stroke::new(2., color));
+        prompts.push(format!(
+            "{}: drop {}",
+            bindings.describe(Action::Grab, "Grab"),
+            name(held)
+        ));
+    } else {
+        painter.circle_filled(center, 2., color);
+    }
+    if let Some(focused) = focus.entity {
+        painter.circle_stroke(center, 6., egui::Stroke::new(1.5, color));
+        let free = grabbables
+            .get(focused)
+            .map_or(false, |(grabbable, mass)| grabbable.is_free(mass));
+        if free && state.grabbing.is_none() {
+            prompts.push(format!(
+                "{}: pick up {}",
+                bindings.describe(Action::Grab, "Grab"),
+                name(focused)
+            ));
+        }
+        if let Ok(interactable) = interactables.get(focused) {
+            prompts.push(format!(
+                "{}: {} {}",
+                bindings.describe(Action::Interact, "Interact"),
+                interactable.verb,
+                name(focused)
+            ));
+            if progress.target == Some(focused) && interactable.hold_secs > 0. {
+                let filled = (progress.held / interactable.hold_secs).min(1.);
+                let points = (0..=32)
+                    .map(|i| {
+                        let angle = TAU * filled * i as f32 / 32.;
+                        center + 10. * egui::vec2(angle.sin(), -angle.cos())
+                    })
+                    .collect();
+                painter.add(egui::Shape::line(points, egui::Stroke::new(3., color)));
+            }
+        }
+    }
+    if prompts.is_empty() {
+        return;
+    }
+
+    egui::Area::new("prompt")
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0., 36.))
+        .show(ctx, |ui| {
+            egui::Frame::none()
+                .fill(egui::Color32::from_black_alpha(96))
+                .inner_margin(egui::style::Margin::same(6.))
+                .show(ui, |ui| {
+                    for prompt in prompts {
+                        ui.label(prompt);
+                    }
+                });
+        });
+}
@@ -0,0 +1,562 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_rapier3d::prelude::*;
+use net::{MatchPhase, Message, NetworkEvent, NetworkResource, ObjectId, Transport};
+
+use crate::actions::*;
+use crate::lag_compensation::*;
+use crate::network::*;
+use crate::player::*;
+use crate::RebuildLevel;
+
+/// How fast doors swing, in degrees per second.
+const DOOR_SPEED: f32 = 180.;
+
+/// Something players can interact with by looking at it and pressing the interact action. What
+/// happens is up to the components next to it, such as a `Door`, which react to `Interacted`.
+#[derive(Component)]
+pub struct Interactable {
+    /// What interacting does, for the prompt, e.g. "open".
+    pub verb: String,
+    /// How long the interact action has to be held down, in seconds. Zero interacts right away.
+    pub hold_secs: f32,
+}
+
+impl Interactable {
+    pub fn new(verb: &str) -> Self {
+        Self {
+            verb: verb.to_string(),
+            hold_secs: 0.,
+        }
+    }
+
+    pub fn hold(mut self, secs: f32) -> Self {
+        self.hold_secs = secs;
+        self
+    }
+}
+
+/// Swings open and shut around the origin of its kinematic body when interacted with.
+#[derive(Component)]
+pub struct Door {
+    pub open: bool,
+    /// How far it swings open around the vertical axis, in degrees.
+    pub open_angle: f32,
+    /// How far it is open right now, in degrees.
+    angle: f32,
+    /// Its rotation when shut.
+    closed: Quat,
+}
+
+impl Door {
+    pub fn new(closed: Quat, open_angle: f32) -> Self {
+        Self {
+            open: false,
+            open_angle,
+            angle: 0.,
+            closed,
+        }
+    }
+}
+
+/// Does nothing by itself, other systems react to its `Interacted` events.
+#[derive(Component)]
+pub struct PushButton;
+
+/// Flips between on and off when interacted with, for other systems to react to.
+#[derive(Component, Default)]
+pub struct Lever {
+    pub on: bool,
+}
+
+/// Goes into the `Inventory` of whoever interacts with it.
+#[derive(Component)]
+pub struct Pickup {
+    pub item: String,
+}
+
+/// Is used up, such as eaten, by whoever interacts with it.
+#[derive(Component)]
+pub struct Consumable;
+
+/// The items a player picked up.
+#[derive(Component, Default)]
+pub struct Inventory(pub Vec<String>);
+
+/// What the local player looks at that it can grab or interact with, from a single ray shared by
+/// grabbing, interacting and the HUD.
+#[derive(Resource, Default)]
+pub struct Focus {
+    pub entity: Option<Entity>,
+    /// How far from the eyes the ray hit it.
+    pub distance: f32,
+}
+
+/// How long the local player has held the interact action on its focus.
+#[derive(Resource, Default)]
+pub struct InteractProgress {
+    pub target: Option<Entity>,
+    /// In seconds.
+    pub held: f32,
+    /// Whether this press already interacted, so holding on doesn't repeat it.
+    done: bool,
+}
+
+/// `NetObject`s that were used up or taken, which the host tells clients joining later about.
+#[derive(Resource, Default)]
+pub struct RemovedObjects(pub Vec<ObjectId>);
+
+/// Sent on every peer when a player interacts with something, once the server agreed to it.
+pub struct Interacted {
+    pub entity: Entity,
+    /// The body of the player that interacted.
+    pub actor: Entity,
+}
+
+/// Sent when the local player finished interacting with something, before the server agreed.
+struct InteractRequested(Entity);
+
+/// Label for interaction systems other plugins need to order against.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, SystemLabel)]
+pub enum InteractSystem {
+    /// Updates the `Focus`.
+    Focus,
+    /// Sends the `Interacted` events.
+    Apply,
+}
+
+/// Lets players interact with `Interactable`s, through the server when they are `NetObject`s
+pub struct InteractPlugin {
+    pub mode: NetworkMode,
+}
+
+impl Plugin for InteractPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Focus>()
+            .init_resource::<InteractProgress>()
+            .init_resource::<RemovedObjects>()
+            .add_event::<Interacted>()
+            .add_event::<InteractRequested>()
+            .add_system(
+                update_focus
+                    .label(InteractSystem::Focus)
+                    .before(PlayerSystem::Grab),
+            )
+            .add_system_set(
+                SystemSet::on_update(MatchPhase::Playing)
+                    .with_system(interact_input.after(InteractSystem::Focus)),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .after(InteractSystem::Apply)
+                    .with_system(toggle_doors)
+                    .with_system(toggle_levers)
+                    .with_system(pick_up)
+                    .with_system(consume),
+            )
+            .add_system(swing_doors.after(toggle_doors));
+
+        match self.mode {
+            NetworkMode::Offline => {
+                app.add_system(
+                    apply_local_interactions
+                        .label(InteractSystem::Apply)
+                        .after(interact_input),
+                );
+            }
+            NetworkMode::Host(_) => {
+                app.add_system(
+                    apply_local_interactions
+                        .label(InteractSystem::Apply)
+                        .after(interact_input),
+                )
+                .add_system(server_interact_handler.label(InteractSystem::Apply))
+                .add_system(send_interactable_state.before(InteractSystem::Apply));
+            }
+            NetworkMode::Client(_) => {
+                app.add_system(
+                    send_interact_requests
+                        .label(InteractSystem::Apply)
+                        .after(interact_input),
+                )
+                .add_system(client_interact_handler.label(InteractSystem::Apply));
+            }
+        }
+    }
+}
+
+/// Whether an `Interactable` can be interacted with, which isn't the case while it's held.
+fn available(grabbable: Option<&Grabbable>) -> bool {
+    grabbable.map_or(true, |grabbable| grabbable.holder.is_none())
+}
+
+/// Casts the grab ray from the camera at whatever the local player could grab or interact with.
+/// Grabbables are left out while it holds something already. Walls and anything else in the way
+/// block the ray, apart from the local body and what it holds.
+fn update_focus(
+    rapier_context: Res<RapierContext>,
+    state: Res<PlayerState>,
+    suspended: Res<InputSuspended>,
+    camera: Query<&GlobalTransform, With<FPSCam>>,
+    body: Query<Entity, With<LocalPlayer>>,
+    grabbables: Query<(Entity, &Grabbable, &ReadMassProperties)>,
+    interactables: Query<(Entity, Option<&Grabbable>), With<Interactable>>,
+    mut focus: ResMut<Focus>,
+) {
+    let eye = match camera.get_single() {
+        Ok(camera) if !suspended.any() => camera.compute_transform(),
+        _ => {
+            focus.entity = None;
+            return;
+        }
+    };
+
+    let mut candidates: HashMap<Entity, bool> = HashMap::new();
+    if state.grabbing.is_none() {
+        for (entity, grabbable, mass) in grabbables.iter() {
+            candidates.insert(entity, grabbable.is_free(mass));
+        }
+    }
+    for (entity, grabbable) in interactables.iter() {
+        if available(grabbable) {
+            candidates.insert(entity, true);
+        }
+    }
+
+    let exclude: Vec<Entity> = body.iter().chain(state.grabbing).collect();
+    let hit = cast_grab_ray(&rapier_context, &eye, &candidates, &exclude);
+    focus.entity = hit.map(|(entity, _)| entity);
+    focus.distance = hit.map_or(0., |(_, distance)| distance);
+}
+
+/// Interacts with the focused `Interactable` once the interact action was held down on it long
+/// enough
+fn interact_input(
+    time: Res<Time>,
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
+    focus: Res<Focus>,
+    interactables: Query<&Interactable>,
+    mut progress: ResMut<InteractProgress>,
+    mut requests: EventWriter<InteractRequested>,
+) {
+    let target = focus
+        .entity
+        .filter(|_| !suspended.any() && actions.pressed(Action::Interact));
+    let interactable = match target.and_then(|target| interactables.get(target).ok()) {
+        Some(interactable) => interactable,
+        None => {
+            *progress = InteractProgress::default();
+            return;
+        }
+    };
+
+    if progress.target == target {
+        progress.held += time.delta_seconds();
+    } else if actions.just_pressed(Action::Interact) {
+        *progress = InteractProgress {
+            target,
+            ..default()
+        };
+    } else {
+        // a press that started on something else doesn't carry over
+        *progress = InteractProgress::default();
+        return;
+    }
+
+    if !progress.done && progress.held >= interactable.hold_secs {
+        progress.done = true;
+        requests.send(InteractRequested(target.unwrap()));
+    }
+}
+
+/// Interacts right away where there's no server to ask, and tells clients about interactions
+/// with `NetObject`s when hosting
+fn apply_local_interactions(
+    mut requests: EventReader<InteractRequested>,
+    mut interactions: EventWriter<Interacted>,
+    mut transport: Option<ResMut<Transport>>,
+    net: Option<Res<NetworkResource>>,
+    players: Res<NetPlayers>,
+    body: Query<Entity, With<LocalPlayer>>,
+    objects: Query<&NetObject>,
+) {
+    let actor = match body.get_single() {
+        Ok(actor) => actor,
+        Err(_) => return,
+    };
+
+    for InteractRequested(entity) in requests.iter() {
+        interactions.send(Interacted {
+            entity: *entity,
+            actor,
+        });
+        if let (Some(transport), Some(net), Some(player), Ok(object)) = (
+            transport.as_mut(),
+            net.as_ref(),
+            players.local,
+            objects.get(*entity),
+        ) {
+            transport.broadcast_reliable(
+                net.connections.keys(),
+                Message::Interacted {
+                    object: object.0,
+                    player,
+                },
+            );
+        }
+    }
+}
+
+/// Asks the server to interact with `NetObject`s, and interacts with anything else right away as
+/// nobody else knows about it
+fn send_interact_requests(
+    mut requests: EventReader<InteractRequested>,
+    mut interactions: EventWriter<Interacted>,
+    mut transport: ResMut<Transport>,
+    body: Query<Entity, With<LocalPlayer>>,
+    objects: Query<&NetObject>,
+) {
+    for InteractRequested(entity) in requests.iter() {
+        match objects.get(*entity) {
+            Ok(object) => transport.send_reliable(Message::Interact(object.0)),
+            Err(_) => {
+                if let Ok(actor) = body.get_single() {
+                    interactions.send(Interacted {
+                        entity: *entity,
+                        actor,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Validates interactions requested by clients and tells everyone about the ones it agrees to.
+/// The object has to be available, and the server's own ray from the player's eyes has to hit it
+/// in the world as the client saw it, like grabs.
+fn server_interact_handler(
+    mut events: EventReader<NetworkEvent>,
+    mut interactions: EventWriter<Interacted>,
+    mut transport: ResMut<Transport>,
+    net: Res<NetworkResource>,
+    players: Res<NetPlayers>,
+    history: Res<PoseHistory>,
+    mut rapier_context: ResMut<RapierContext>,
+    bodies: Query<(&Transform, &ControlInput), With<FPSBody>>,
+    objects: Query<(Entity, &NetObject, Option<&Grabbable>), With<Interactable>>,
+    grabbables: Query<(Entity, &Grabbable)>,
+) {
+    for event in events.iter() {
+        let (addr, object) = match event {
+            NetworkEvent::Message(addr, Message::Interact(object)) => (addr, object),
+            _ => continue,
+        };
+        let (id, body) = match players
+            .addresses
+            .get(addr)
+            .and_then(|id| players.entities.get(id).map(|body| (*id, *body)))
+        {
+            Some(player) => player,
+            None => continue,
+        };
+        let (entity, _, grabbable) = match objects.iter().find(|(_, o, ..)| o.0 == *object) {
+            Some(object) => object,
+            None => continue,
+        };
+
+        // what the player holds doesn't block its view, as on the client
+        let exclude: Vec<Entity> = std::iter::once(body)
+            .chain(
+                grabbables
+                    .iter()
+                    .filter(|(_, grabbable)| grabbable.holder == Some(body))
+                    .map(|(held, _)| held),
+            )
+            .collect();
+        let candidates: HashMap<Entity, bool> = std::iter::once((entity, true)).collect();
+        let hit = bodies.get(body).ok().and_then(|(transform, input)| {
+            let eye = eye_transform(transform, &input.0);
+            let view_time = history.view_time(input.0.view_tick, input.0.interpolation_delay);
+            rewind(
+                &mut rapier_context,
+                &history,
+                view_time,
+                Some(body),
+                |context| cast_grab_ray(context, &eye, &candidates, &exclude),
+            )
+        });
+        if hit.is_none() || !available(grabbable) {
+            debug!("{}: denied interaction with object {}", addr, object);
+            continue;
+        }
+
+        interactions.send(Interacted {
+            entity,
+            actor: body,
+        });
+        transport.broadcast_reliable(
+            net.connections.keys(),
+            Message::Interacted {
+                object: *object,
+                player: id,
+            },
+        );
+    }
+}
+
+/// Tells clients that join which `NetObject`s were used up or taken and which are switched on,
+/// as every peer starts out with the level as it was built. Runs before this frame's
+/// interactions, which are sent after it.
+fn send_interactable_state(
+    mut events: EventReader<NetworkEvent>,
+    mut transport: ResMut<Transport>,
+    removed: Res<RemovedObjects>,
+    switches: Query<(&NetObject, Option<&Door>, Option<&Lever>)>,
+) {
+    for event in events.iter() {
+        let addr = match event {
+            NetworkEvent::Connected(addr) => addr,
+            _ => continue,
+        };
+        for object in removed.0.iter() {
+            transport.send_reliable_to(*addr, Message::ObjectRemoved(*object));
+        }
+        for (object, door, lever) in switches.iter() {
+            let on = door.map(|door| door.open).or(lever.map(|lever| lever.on));
+            if let Some(on) = on {
+                transport.send_reliable_to(
+                    *addr,
+                    Message::SwitchState {
+                        object: object.0,
+                        on,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Applies the interactions the server agreed to, and the state of the level it had when we
+/// joined or rebuilt it
+fn client_interact_handler(
+    mut commands: Commands,
+    mut events: EventReader<NetworkEvent>,
+    mut interactions: EventWriter<Interacted>,
+    mut rebuilds: EventWriter<RebuildLevel>,
+    mut removed: ResMut<RemovedObjects>,
+    players: Res<NetPlayers>,
+    objects: Query<(Entity, &NetObject)>,
+    mut doors: Query<(&mut Door, &mut Interactable)>,
+    mut levers: Query<&mut Lever>,
+) {
+    let find = |object: &ObjectId| {
+        objects
+            .iter()
+            .find(|(_, o)| o.0 == *object)
+            .map(|(entity, _)| entity)
+    };
+    for event in events.iter() {
+        match event {
+            NetworkEvent::Message(_, Message::Interacted { object, player }) => {
+                if let (Some(entity), Some(actor)) = (find(object), players.entities.get(player)) {
+                    interactions.send(Interacted {
+                        entity,
+                        actor: *actor,
+                    });
+                }
+            }
+            NetworkEvent::Message(_, Message::LevelReloaded) => rebuilds.send(RebuildLevel),
+            NetworkEvent::Message(_, Message::ObjectRemoved(object)) => {
+                if let Some(entity) = find(object) {
+                    commands.entity(entity).despawn_recursive();
+                    removed.0.push(*object);
+                }
+            }
+            NetworkEvent::Message(_, Message::SwitchState { object, on }) => {
+                let entity = match find(object) {
+                    Some(entity) => entity,
+                    None => continue,
+                };
+                if let Ok((mut door, mut interactable)) = doors.get_mut(entity) {
+                    open_door(&mut door, &mut interactable, *on);
+                }
+                if let Ok(mut lever) = levers.get_mut(entity) {
+                    lever.on = *on;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn open_door(door: &mut Door, interactable: &mut Interactable, open: bool) {
+    door.open = open;
+    interactable.verb = if open { "close" } else { "open" }.to_string();
+}
+
+fn toggle_doors(
+    mut interactions: EventReader<Interacted>,
+    mut doors: Query<(&mut Door, &mut Interactable)>,
+) {
+    for interaction in interactions.iter() {
+        if let Ok((mut door, mut interactable)) = doors.get_mut(interaction.entity) {
+            let open = !door.open;
+            open_door(&mut door, &mut interactable, open);
+        }
+    }
+}
+
+/// Swings doors towards being open or shut
+fn swing_doors(time: Res<Time>, mut doors: Query<(&mut Door, &mut Transform)>) {
+    for (mut door, mut transform) in doors.iter_mut() {
+        let target = if door.open { door.open_angle } else { 0. };
+        if door.angle == target {
+            continue;
+        }
+        let step = DOOR_SPEED * time.delta_seconds();
+        door.angle += (target - door.angle).clamp(-step, step);
+        transform.rotation = door.closed * Quat::from_rotation_y(door.angle.to_radians());
+    }
+}
+
+fn toggle_levers(mut interactions: EventReader<Interacted>, mut levers: Query<&mut Lever>) {
+    for interaction in interactions.iter() {
+        if let Ok(mut lever) = levers.get_mut(interaction.entity) {
+            lever.on = !lever.on;
+        }
+    }
+}
+
+/// Moves pickups into the inventory of whoever picked them up
+fn pick_up(
+    mut commands: Commands,
+    mut interactions: EventReader<Interacted>,
+    mut removed: ResMut<RemovedObjects>,
+    pickups: Query<(&Pickup, Option<&NetObject>)>,
+    mut inventories: Query<&mut Inventory>,
+) {
+    for interaction in interactions.iter() {
+        if let Ok((pickup, object)) = pickups.get(interaction.entity) {
+            if let Ok(mut inventory) = inventories.get_mut(interaction.actor) {
+                inventory.0.push(pickup.item.clone());
+            }
+            commands.entity(interaction.entity).despawn_recursive();
+            removed.0.extend(object.map(|object| object.0));
+        }
+    }
+}
+
+fn consume(
+    mut commands: Commands,
+    mut interactions: EventReader<Interacted>,
+    mut removed: ResMut<RemovedObjects>,
+    consumables: Query<Option<&NetObject>, With<Consumable>>,
+) {
+    for interaction in interactions.iter() {
+        if let Ok(object) = consumables.get(interaction.entity) {
+            commands.entity(interaction.entity).despawn_recursive();
+            removed.0.extend(object.map(|object| object.0));
+        }
+    }
+}
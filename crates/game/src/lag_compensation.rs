@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use bevy_rapier3d::rapier::math::{Isometry, Real};
+use bevy_rapier3d::rapier::prelude::ColliderHandle;
+use net::NetworkTick;
+
+use crate::network::*;
+use crate::player::FPSBody;
+
+/// How much history the server keeps, which bounds how far back a client can rewind.
+const HISTORY_SECS: f64 = 1.;
+
+/// The pose of every moving collider at one server tick.
+struct PoseSnapshot {
+    tick: u64,
+    /// Seconds since startup.
+    time: f64,
+    poses: Vec<(Entity, ColliderHandle, Isometry<Real>)>,
+}
+
+/// Recent poses of the colliders that move, so the server can run queries against the world as
+/// a client saw it.
+#[derive(Resource, Default)]
+pub struct PoseHistory {
+    snapshots: VecDeque<PoseSnapshot>,
+}
+
+impl PoseHistory {
+    /// Returns the time, in seconds since startup, a client saw while showing remote state
+    /// `interpolation_delay` seconds behind `view_tick`. Clamped to the recorded history, so
+    /// clients can neither rewind further than it nor into the future.
+    pub fn view_time(&self, view_tick: u64, interpolation_delay: f32) -> f64 {
+        let (oldest, newest) = match (self.snapshots.front(), self.snapshots.back()) {
+            (Some(oldest), Some(newest)) => (oldest, newest),
+            _ => return 0.,
+        };
+        let tick_time = self
+            .snapshots
+            .iter()
+            .find(|snapshot| snapshot.tick >= view_tick)
+            .map_or(newest.time, |snapshot| snapshot.time);
+
+        (tick_time - interpolation_delay.max(0.) as f64).clamp(oldest.time, newest.time)
+    }
+
+    /// Poses of the recorded colliders at `time`, interpolated between the closest two ticks.
+    fn poses_at(&self, time: f64) -> Vec<(Entity, ColliderHandle, Isometry<Real>)> {
+        let after = match self.snapshots.iter().position(|s| s.time >= time) {
+            Some(after) => after,
+            None => {
+                return self
+                    .snapshots
+                    .back()
+                    .map(|s| s.poses.clone())
+                    .unwrap_or_default()
+            }
+        };
+        let next = &self.snapshots[after];
+        if after == 0 || next.time == time {
+            return next.poses.clone();
+        }
+
+        let prev = &self.snapshots[after - 1];
+        let t = ((time - prev.time) / (next.time - prev.time)) as Real;
+        prev.poses
+            .iter()
+            .map(
+                |(entity, handle, pose)| match next.poses.iter().find(|(_, h, _)| h == handle) {
+                    Some((_, _, next_pose)) => (*entity, *handle, pose.lerp_slerp(next_pose, t)),
+                    None => (*entity, *handle, *pose),
+                },
+            )
+            .collect()
+    }
+}
+
+/// Moves the recorded colliders back to where they were at `time`, runs `query` against that
+/// world and then restores the present. `exclude` keeps one collider, usually the querying
+/// player's own body, where it is now.
+pub fn rewind<T>(
+    rapier_context: &mut RapierContext,
+    history: &PoseHistory,
+    time: f64,
+    exclude: Option<Entity>,
+    query: impl FnOnce(&RapierContext) -> T,
+) -> T {
+    let mut present = Vec::new();
+    for (entity, handle, pose) in history.poses_at(time) {
+        if Some(entity) == exclude {
+            continue;
+        }
+        if let Some(collider) = rapier_context.colliders.get_mut(handle) {
+            present.push((handle, *collider.position()));
+            collider.set_position(pose);
+        }
+    }
+    update_query_pipeline(rapier_context);
+
+    let result = query(rapier_context);
+
+    for (handle, pose) in present {
+        if let Some(collider) = rapier_context.colliders.get_mut(handle) {
+            collider.set_position(pose);
+        }
+    }
+    update_query_pipeline(rapier_context);
+
+    result
+}
+
+fn update_query_pipeline(rapier_context: &mut RapierContext) {
+    let RapierContext {
+        query_pipeline,
+        bodies,
+        colliders,
+        ..
+    } = rapier_context;
+    query_pipeline.update(bodies, colliders);
+}
+
+/// Records the poses of players and networked objects every server tick
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PoseHistory>()
+            .add_system(record_poses.after(MultiplayerSystem::Tick));
+    }
+}
+
+fn record_poses(
+    time: Res<Time>,
+    network_tick: Res<NetworkTick>,
+    tick: Res<ServerTick>,
+    rapier_context: Res<RapierContext>,
+    mut history: ResMut<PoseHistory>,
+    colliders: Query<(Entity, &RapierColliderHandle), Or<(With<FPSBody>, With<NetObject>)>>,
+) {
+    if !network_tick.just_ticked() {
+        return;
+    }
+
+    let now = time.elapsed_seconds_f64();
+    let poses = colliders
+        .iter()
+        .filter_map(|(entity, handle)| {
+            rapier_context
+                .colliders
+                .get(handle.0)
+                .map(|collider| (entity, handle.0, *collider.position()))
+        })
+        .collect();
+    history.snapshots.push_back(PoseSnapshot {
+        tick: tick.0,
+        time: now,
+        poses,
+    });
+
+    while history
+        .snapshots
+        .front()
+        .map_or(false, |oldest| now - oldest.time > HISTORY_SECS)
+    {
+        history.snapshots.pop_front();
+    }
+}
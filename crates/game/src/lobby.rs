@@ -0,0 +1,290 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use bevy_egui::{egui, EguiContext};
+#[cfg(not(target_arch = "wasm32"))]
+use net::ReloadLevel;
+use net::{MatchPhase, Message, NetworkEvent, NetworkResource, PlayerId, Transport};
+
+use crate::actions::*;
+use crate::network::*;
+use crate::player::*;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::RebuildLevel;
+
+/// How many players have to be connected, the host included, before a match can start.
+const MIN_PLAYERS: usize = 2;
+/// How long the countdown before a match lasts, in seconds.
+const COUNTDOWN_SECS: f32 = 5.;
+/// How long a match lasts, in seconds.
+const MATCH_SECS: f32 = 300.;
+/// How long the results are shown before going back to the lobby, in seconds.
+const RESULTS_SECS: f32 = 10.;
+
+/// Where the match stands, as far as this peer knows. The phase itself is the
+/// `State<MatchPhase>`.
+#[derive(Resource, Default)]
+pub struct MatchStatus {
+    /// Players ready for the match to start.
+    pub ready: HashSet<PlayerId>,
+    /// Seconds left of the current phase, `None` when it lasts until players are ready.
+    pub remaining: Option<f32>,
+}
+
+impl MatchStatus {
+    fn message(&self, phase: MatchPhase) -> Message {
+        Message::MatchState {
+            phase,
+            remaining: self.remaining,
+            ready: self.ready.iter().copied().collect(),
+        }
+    }
+}
+
+/// Sent when the local player triggers `Action::Ready`.
+struct ReadyToggled;
+
+fn phase_duration(phase: MatchPhase) -> Option<f32> {
+    match phase {
+        MatchPhase::Lobby => None,
+        MatchPhase::Countdown => Some(COUNTDOWN_SECS),
+        MatchPhase::Playing => Some(MATCH_SECS),
+        MatchPhase::Results => Some(RESULTS_SECS),
+    }
+}
+
+/// Runs matches through Lobby, Countdown, Playing and Results and back to the Lobby. The host
+/// decides when to move on and replicates the phase to its clients, single player goes straight
+/// to Playing.
+pub struct MatchPlugin {
+    pub mode: NetworkMode,
+}
+
+impl Plugin for MatchPlugin {
+    fn build(&self, app: &mut App) {
+        if self.mode == NetworkMode::Offline {
+            app.add_state(MatchPhase::Playing);
+            return;
+        }
+
+        app.add_state(MatchPhase::Lobby)
+            .init_resource::<MatchStatus>()
+            .add_event::<ReadyToggled>()
+            .add_system(ready_input.after(PlayerSystem::Input))
+            .add_system(match_hud);
+
+        match self.mode {
+            NetworkMode::Offline => {}
+            NetworkMode::Host(_) => {
+                app.add_system(run_match.after(ready_input));
+            }
+            NetworkMode::Client(_) => {
+                app.add_system(client_match_handler)
+                    .add_system(send_ready.after(ready_input));
+            }
+        }
+    }
+}
+
+fn ready_input(
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
+    phase: Res<State<MatchPhase>>,
+    mut toggles: EventWriter<ReadyToggled>,
+) {
+    let can_ready = matches!(phase.current(), MatchPhase::Lobby | MatchPhase::Countdown);
+    if can_ready && !suspended.any() && actions.just_pressed(Action::Ready) {
+        toggles.send(ReadyToggled);
+    }
+}
+
+/// Takes ready-ups from the host and clients and moves the match on to the next phase when it's
+/// time, telling clients whenever anything changed
+fn run_match(
+    time: Res<Time>,
+    mut events: EventReader<NetworkEvent>,
+    mut toggles: EventReader<ReadyToggled>,
+    players: Res<NetPlayers>,
+    net: Res<NetworkResource>,
+    mut transport: ResMut<Transport>,
+    mut phase: ResMut<State<MatchPhase>>,
+    mut status: ResMut<MatchStatus>,
+) {
+    let current = *phase.current();
+    let can_ready = matches!(current, MatchPhase::Lobby | MatchPhase::Countdown);
+    let mut changed = false;
+
+    for event in events.iter() {
+        match event {
+            NetworkEvent::Connected(addr) => {
+                transport.send_reliable_to(*addr, status.message(current));
+            }
+            NetworkEvent::Message(addr, Message::Ready(ready)) if can_ready => {
+                if let Some(id) = players.addresses.get(addr) {
+                    changed |= if *ready {
+                        status.ready.insert(*id)
+                    } else {
+                        status.ready.remove(id)
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(host) = players.local {
+        for _ in toggles.iter() {
+            if !status.ready.remove(&host) {
+                status.ready.insert(host);
+            }
+            changed = true;
+        }
+    }
+
+    // forget players that left
+    let ready = status.ready.len();
+    status.ready.retain(|id| players.entities.contains_key(id));
+    changed |= status.ready.len() != ready;
+
+    if let Some(remaining) = &mut status.remaining {
+        *remaining -= time.delta_seconds();
+    }
+    let connected = players.entities.len();
+    let all_ready = connected >= MIN_PLAYERS && status.ready.len() == connected;
+    let phase_over = status.remaining.map_or(false, |remaining| remaining <= 0.);
+
+    let next = match current {
+        MatchPhase::Lobby if all_ready => MatchPhase::Countdown,
+        MatchPhase::Countdown if !all_ready => MatchPhase::Lobby,
+        MatchPhase::Countdown if phase_over => MatchPhase::Playing,
+        MatchPhase::Playing if phase_over => MatchPhase::Results,
+        MatchPhase::Results if phase_over => MatchPhase::Lobby,
+        _ => current,
+    };
+    if next != current {
+        info!("match phase {:?} -> {:?}", current, next);
+        status.remaining = phase_duration(next);
+        if next == MatchPhase::Playing {
+            status.ready.clear();
+        }
+        if let Err(e) = phase.overwrite_set(next) {
+            warn!("could not change the match phase: {:?}", e);
+        }
+        changed = true;
+    }
+
+    if changed {
+        transport.broadcast_reliable(net.connections.keys(), status.message(next));
+    }
+}
+
+/// Rebuilds the level on every peer and starts the match over from the lobby when the admin
+/// console reloads the level.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn reload_level(
+    mut reloads: EventReader<ReloadLevel>,
+    mut rebuilds: EventWriter<RebuildLevel>,
+    net: Res<NetworkResource>,
+    mut transport: ResMut<Transport>,
+    mut phase: ResMut<State<MatchPhase>>,
+    mut status: ResMut<MatchStatus>,
+) {
+    if reloads.iter().count() == 0 {
+        return;
+    }
+    info!("reloading, match phase {:?} -> Lobby", phase.current());
+    rebuilds.send(RebuildLevel);
+    transport.broadcast_reliable(net.connections.keys(), Message::LevelReloaded);
+    status.ready.clear();
+    status.remaining = phase_duration(MatchPhase::Lobby);
+    if *phase.current() != MatchPhase::Lobby {
+        if let Err(e) = phase.overwrite_set(MatchPhase::Lobby) {
+            warn!("could not change the match phase: {:?}", e);
+        }
+    }
+    transport.broadcast_reliable(net.connections.keys(), status.message(MatchPhase::Lobby));
+}
+
+fn send_ready(
+    mut toggles: EventReader<ReadyToggled>,
+    players: Res<NetPlayers>,
+    status: Res<MatchStatus>,
+    mut transport: ResMut<Transport>,
+) {
+    if let Some(local) = players.local {
+        for _ in toggles.iter() {
+            transport.send_reliable(Message::Ready(!status.ready.contains(&local)));
+        }
+    }
+}
+
+/// Follows the phase the server replicates
+fn client_match_handler(
+    time: Res<Time>,
+    mut events: EventReader<NetworkEvent>,
+    mut phase: ResMut<State<MatchPhase>>,
+    mut status: ResMut<MatchStatus>,
+) {
+    if let Some(remaining) = &mut status.remaining {
+        *remaining = (*remaining - time.delta_seconds()).max(0.);
+    }
+
+    for event in events.iter() {
+        if let NetworkEvent::Message(
+            _,
+            Message::MatchState {
+                phase: next,
+                remaining,
+                ready,
+            },
+        ) = event
+        {
+            status.remaining = *remaining;
+            status.ready = ready.iter().copied().collect();
+            if phase.current() != next {
+                if let Err(e) = phase.overwrite_set(*next) {
+                    warn!("could not change the match phase: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Shows the phase of the match at the top of the screen
+fn match_hud(
+    mut egui_context: ResMut<EguiContext>,
+    phase: Res<State<MatchPhase>>,
+    status: Res<MatchStatus>,
+    players: Res<NetPlayers>,
+    bindings: Res<InputBindings>,
+) {
+    let remaining = status.remaining.unwrap_or(0.).ceil() as u32;
+    let text = match phase.current() {
+        MatchPhase::Lobby => {
+            let connected = players.entities.len();
+            let local_ready = players
+                .local
+                .map_or(false, |local| status.ready.contains(&local));
+            let mut text = format!("Lobby: {}/{} ready", status.ready.len(), connected);
+            if connected < MIN_PLAYERS {
+                text += &format!(", waiting for at least {} players", MIN_PLAYERS);
+            }
+            let key = bindings.describe(Action::Ready, "the ready button");
+            if local_ready {
+                text + &format!(". Press {} to cancel", key)
+            } else {
+                text + &format!(". Press {} when ready", key)
+            }
+        }
+        MatchPhase::Countdown => format!("Match starts in {}", remaining),
+        MatchPhase::Playing => format!("{}:{:02}", remaining / 60, remaining % 60),
+        MatchPhase::Results => format!("Match over, back to the lobby in {}", remaining),
+    };
+
+    egui::Area::new("match")
+        .anchor(egui::Align2::CENTER_TOP, egui::vec2(0., 8.))
+        .show(egui_context.ctx_mut(), |ui| {
+            egui::Frame::none()
+                .fill(egui::Color32::from_black_alpha(96))
+                .inner_margin(egui::style::Margin::same(6.))
+                .show(ui, |ui| ui.label(text));
+        });
+}
@@ -1,9 +1,27 @@
+mod actions;
+mod authority;
+mod character;
+mod chat;
+mod hud;
+mod interact;
+mod lag_compensation;
+mod lobby;
+mod menu;
+mod network;
 mod player;
+mod settings;
+use actions::ActionPlugin;
 use bevy::prelude::*;
+use bevy_egui::EguiPlugin;
 use bevy_rapier3d::prelude::*;
 use bevy_rapier3d::rapier::prelude::MassProperties;
+use hud::HudPlugin;
 use inline_tweak::*;
+use interact::*;
+use menu::MenuPlugin;
+use network::*;
 use player::*;
+use settings::SettingsPlugin;
 
 use std::f32::consts::PI;
 
@@ -16,22 +34,104 @@ struct Rotatable {
 }
 
 fn main() {
+    let mode = NetworkMode::from_args();
     App::new()
         .add_plugins(DefaultPlugins)
+        .add_plugin(EguiPlugin)
+        .add_plugin(SettingsPlugin)
+        .add_plugin(ActionPlugin)
         .add_plugin(PlayerPlugin)
+        .add_plugin(InteractPlugin { mode })
+        .add_plugin(MenuPlugin)
+        .add_plugin(HudPlugin)
+        .add_plugin(MultiplayerPlugin { mode })
         .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
         //.add_plugin(RapierDebugRenderPlugin::default())
         //.add_plugin(WorldInspectorPlugin::new())
+        .add_event::<RebuildLevel>()
         .add_startup_system(setup)
-        .insert_resource(MovementSettings::default())
+        .add_system(rebuild_level)
         .run();
 }
 
+/// Sent to put the level's `NetObject`s back the way they were at startup, when the host reloads
+/// the level.
+pub struct RebuildLevel;
+
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     asset_server: Res<AssetServer>,
+) {
+    spawn_level_objects(&mut commands, &mut meshes, &mut materials, &asset_server);
+
+    //
+    // Add a light source for better 3d visibility.
+    //
+    const HALF_SIZE: f32 = 10.0;
+    commands.spawn_bundle(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            // Configure the projection to better fit the scene
+            shadow_projection: OrthographicProjection {
+                left: -HALF_SIZE,
+                right: HALF_SIZE,
+                bottom: -HALF_SIZE,
+                top: HALF_SIZE,
+                near: -10.0 * HALF_SIZE,
+                far: 10.0 * HALF_SIZE,
+                ..default()
+            },
+            shadows_enabled: true,
+            ..default()
+        },
+        transform: Transform {
+            translation: Vec3::new(0.0, 2.0, 0.0),
+            rotation: Quat::from_rotation_x(-PI / 4.),
+            ..default()
+        },
+        ..default()
+    });
+    //
+}
+
+/// Despawns the level's `NetObject`s, along with everything players took from them, and spawns
+/// them anew
+fn rebuild_level(
+    mut rebuilds: EventReader<RebuildLevel>,
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    asset_server: Res<AssetServer>,
+    mut state: ResMut<PlayerState>,
+    mut removed: ResMut<RemovedObjects>,
+    objects: Query<Entity, Or<(With<NetObject>, With<GrabHand>)>>,
+    mut inventories: Query<&mut Inventory>,
+) {
+    if rebuilds.iter().count() == 0 {
+        return;
+    }
+    info!("rebuilding the level");
+    for entity in objects.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    for mut inventory in inventories.iter_mut() {
+        inventory.0.clear();
+    }
+    removed.0.clear();
+    state.grabbing = None;
+    state.throw_charge = None;
+    state.rotating = false;
+    spawn_level_objects(&mut commands, &mut meshes, &mut materials, &asset_server);
+}
+
+/// Spawns the objects players can grab and interact with, each with the same `NetObject` id on
+/// every peer.
+fn spawn_level_objects(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    asset_server: &AssetServer,
 ) {
     let material_handle = materials.add(StandardMaterial {
         base_color: Color::rgb(0.8, 0.7, 0.6),
@@ -51,9 +151,18 @@ fn setup(
         .insert(Collider::ball(0.5))
         .insert(Velocity::default())
         .insert(ExternalForce::default())
+        .insert(ExternalImpulse::default())
+        .insert(ReadMassProperties::default())
         .insert(GravityScale::default())
         .insert(ColliderMassProperties::Density(5.0))
-        .insert(Grabbable::default())
+        .insert(Grabbable {
+            hold: HoldMode::Joint(JointHold::default()),
+            ..default()
+        })
+        .insert(NetObject(1))
+        .insert(Name::new("apple"))
+        .insert(Interactable::new("eat").hold(1.))
+        .insert(Consumable)
         .insert(Damping {
             linear_damping: 1.,
             ..Default::default()
@@ -69,41 +178,48 @@ fn setup(
         .insert(Collider::capsule_x(0.4, 0.4 / 2.))
         .insert(Velocity::default())
         .insert(ExternalForce::default())
+        .insert(ExternalImpulse::default())
+        .insert(ReadMassProperties::default())
         .insert(ColliderMassProperties::Density(5.0))
         .insert(GravityScale::default())
-        .insert(Grabbable::default())
+        .insert(Grabbable {
+            // held level, pointing across the view
+            snap_rotation: Some(Quat::IDENTITY),
+            ..default()
+        })
+        .insert(NetObject(2))
+        .insert(Name::new("croissant"))
+        .insert(Interactable::new("take"))
+        .insert(Pickup {
+            item: "croissant".to_string(),
+        })
         .insert(Damping {
             linear_damping: 1.,
             ..Default::default()
         });
 
-    //
-    // Add a light source for better 3d visibility.
-    //
-    const HALF_SIZE: f32 = 10.0;
-    commands.spawn_bundle(DirectionalLightBundle {
-        directional_light: DirectionalLight {
-            // Configure the projection to better fit the scene
-            shadow_projection: OrthographicProjection {
-                left: -HALF_SIZE,
-                right: HALF_SIZE,
-                bottom: -HALF_SIZE,
-                top: HALF_SIZE,
-                near: -10.0 * HALF_SIZE,
-                far: 10.0 * HALF_SIZE,
+    // A door hinged on its left edge, which swings open when interacted with.
+    commands
+        .spawn_bundle(TransformBundle::from(Transform::from_xyz(3., 0.2, 0.)))
+        .insert(VisibilityBundle::default())
+        .insert(RigidBody::KinematicPositionBased)
+        .insert(Collider::compound(vec![(
+            Vec3::new(0.5, 1., 0.),
+            Quat::IDENTITY,
+            Collider::cuboid(0.5, 1., 0.05),
+        )]))
+        .insert(NetObject(3))
+        .insert(Name::new("door"))
+        .insert(Interactable::new("open"))
+        .insert(Door::new(Quat::IDENTITY, 90.))
+        .with_children(|door| {
+            door.spawn_bundle(PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Box::new(1., 2., 0.1))),
+                material: material_handle,
+                transform: Transform::from_xyz(0.5, 1., 0.),
                 ..default()
-            },
-            shadows_enabled: true,
-            ..default()
-        },
-        transform: Transform {
-            translation: Vec3::new(0.0, 2.0, 0.0),
-            rotation: Quat::from_rotation_x(-PI / 4.),
-            ..default()
-        },
-        ..default()
-    });
-    //
+            });
+        });
 }
 
 // This system will rotate any entity in the scene with an assigned Rotatable around its z-axis.
@@ -0,0 +1,235 @@
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::window::CursorGrabMode;
+use bevy_egui::{egui, EguiContext};
+use bevy_rapier3d::prelude::*;
+
+use crate::actions::*;
+use crate::network::NetworkMode;
+use crate::player::*;
+use crate::settings::Settings;
+
+/// What the menus suspend input as.
+const MENU: &str = "menu";
+
+/// Whether the player is in a menu or in the game. The mouse cursor is only captured and player
+/// input only read while Playing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GameState {
+    /// The title screen shown at startup. The world, and any match, carries on behind it.
+    MainMenu,
+    Playing,
+    /// The pause menu is open over the game. The simulation stops meanwhile in single-player,
+    /// while everyone else carries on in multiplayer.
+    Paused,
+}
+
+/// Which page of the main or pause menu is shown.
+#[derive(Resource, Default)]
+struct MenuPage {
+    /// Whether the options are shown rather than the main page.
+    options: bool,
+}
+
+/// Adds the `GameState`, with a main menu, a pause menu and an options screen for the player's
+/// `Settings`
+pub struct MenuPlugin;
+
+impl Plugin for MenuPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_state(GameState::MainMenu)
+            .init_resource::<MenuPage>()
+            .add_system(menu_input.before(PlayerSystem::Input))
+            .add_system(menu)
+            .add_system_set(SystemSet::on_enter(GameState::MainMenu).with_system(leave_game))
+            .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(enter_game))
+            .add_system_set(SystemSet::on_update(GameState::Playing).with_system(recapture_cursor))
+            .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(leave_game));
+    }
+}
+
+fn change_state(state: &mut State<GameState>, next: GameState) {
+    if let Err(e) = state.set(next) {
+        warn!("could not change the game state: {:?}", e);
+    }
+}
+
+/// Captures the cursor and hands input back to the player, and starts the simulation again
+fn enter_game(
+    mode: Res<NetworkMode>,
+    mut rapier_config: ResMut<RapierConfiguration>,
+    mut suspended: ResMut<InputSuspended>,
+    mut windows: ResMut<Windows>,
+) {
+    let window = windows.get_primary_mut().unwrap();
+    window.set_cursor_grab_mode(CursorGrabMode::Locked);
+    suspended.set(MENU, false);
+    if *mode == NetworkMode::Offline {
+        rapier_config.physics_pipeline_active = true;
+    }
+}
+
+/// Lets go of the cursor and suspends player input for the menus. Single-player stops the
+/// simulation as well, as there's nobody else to keep playing.
+fn leave_game(
+    mode: Res<NetworkMode>,
+    mut rapier_config: ResMut<RapierConfiguration>,
+    mut suspended: ResMut<InputSuspended>,
+    mut windows: ResMut<Windows>,
+) {
+    let window = windows.get_primary_mut().unwrap();
+    window.set_cursor_grab_mode(CursorGrabMode::None);
+    suspended.set(MENU, true);
+    if *mode == NetworkMode::Offline {
+        rapier_config.physics_pipeline_active = false;
+    }
+}
+
+/// Takes the cursor back after something else, such as switching windows, let go of it
+fn recapture_cursor(actions: Res<ActionState>, mut windows: ResMut<Windows>) {
+    if actions.just_pressed(Action::CaptureCursor) {
+        let window = windows.get_primary_mut().unwrap();
+        window.set_cursor_grab_mode(CursorGrabMode::Locked);
+    }
+}
+
+/// Pauses and resumes the game with `Action::Menu`, which also backs out of the options
+fn menu_input(
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
+    settings: Res<MovementSettings>,
+    bindings: Res<InputBindings>,
+    mut state: ResMut<State<GameState>>,
+    mut page: ResMut<MenuPage>,
+) {
+    if !actions.just_pressed(Action::Menu) || suspended.by_others(MENU) {
+        return;
+    }
+    if page.options {
+        close_options(&mut page, &settings, &bindings);
+        return;
+    }
+    match state.current() {
+        GameState::Playing => change_state(&mut state, GameState::Paused),
+        GameState::Paused => change_state(&mut state, GameState::Playing),
+        GameState::MainMenu => {}
+    }
+}
+
+/// Shows the main menu or the pause menu, whichever the `GameState` calls for
+fn menu(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameState>>,
+    mut page: ResMut<MenuPage>,
+    mut settings: ResMut<MovementSettings>,
+    bindings: Res<InputBindings>,
+    mode: Res<NetworkMode>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let current = *state.current();
+    if current == GameState::Playing {
+        return;
+    }
+
+    let title = match (page.options, current) {
+        (true, _) => "Options",
+        (false, GameState::Paused) => "Paused",
+        (false, _) => "Main menu",
+    };
+    let mut play = false;
+    egui::Window::new(title)
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0., 0.))
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            if page.options {
+                options(ui, &mut settings, *mode);
+                if ui.button("Back").clicked() {
+                    close_options(&mut page, &settings, &bindings);
+                }
+            } else {
+                let label = if current == GameState::Paused {
+                    "Resume"
+                } else {
+                    "Play"
+                };
+                play = ui.button(label).clicked();
+                if ui.button("Options").clicked() {
+                    page.options = true;
+                }
+                if ui.button("Quit").clicked() {
+                    exit.send(AppExit);
+                }
+            }
+        });
+
+    if play {
+        change_state(&mut state, GameState::Playing);
+    }
+}
+
+/// Sliders for the `MovementSettings`, which take effect as soon as they are moved. How players
+/// move is only up to us in single-player, in multiplayer every peer has to agree on it.
+fn options(ui: &mut egui::Ui, settings: &mut ResMut<MovementSettings>, mode: NetworkMode) {
+    let offline = mode == NetworkMode::Offline;
+    let mut edited = MovementSettings::clone(settings);
+    // shown as a multiple of a more readable unit
+    let mut sensitivity = edited.sensitivity * 10_000.;
+
+    let mut changed = false;
+    changed |= ui
+        .add(egui::Slider::new(&mut sensitivity, 0.2..=5.).text("Mouse sensitivity"))
+        .changed();
+    changed |= ui
+        .add(
+            egui::Slider::new(&mut edited.fov, 60.0..=120.)
+                .suffix("°")
+                .text("Field of view"),
+        )
+        .changed();
+    for (value, range, text) in [
+        (&mut edited.max_speed, 2.0..=20., "Walking speed"),
+        (&mut edited.acceleration, 10.0..=300., "Acceleration"),
+        (&mut edited.jump_speed, 2.0..=12., "Jump speed"),
+    ] {
+        changed |= ui
+            .add_enabled(offline, egui::Slider::new(value, range).text(text))
+            .on_disabled_hover_text("Only in single-player")
+            .changed();
+    }
+    ui.horizontal(|ui| {
+        ui.label("Snap held objects to");
+        for (label, snap) in [("Off", None), ("15°", Some(15.)), ("45°", Some(45.))] {
+            changed |= ui
+                .radio_value(&mut edited.rotation_snap, snap, label)
+                .changed();
+        }
+    });
+    if ui.button("Reset to defaults").clicked() {
+        let defaults = MovementSettings::default();
+        sensitivity = defaults.sensitivity * 10_000.;
+        if offline {
+            edited = defaults;
+        } else {
+            edited.fov = defaults.fov;
+            edited.rotation_snap = defaults.rotation_snap;
+        }
+        changed = true;
+    }
+
+    if changed {
+        edited.sensitivity = sensitivity / 10_000.;
+        **settings = edited;
+    }
+}
+
+/// Goes back to the main page, saving whatever was changed in the options. The bindings are
+/// saved along with them, so the file is there for players to edit.
+fn close_options(page: &mut MenuPage, settings: &MovementSettings, bindings: &InputBindings) {
+    page.options = false;
+    Settings {
+        movement: settings.clone(),
+    }
+    .save();
+    bindings.save();
+}
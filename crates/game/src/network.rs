@@ -0,0 +1,848 @@
+use std::env;
+use std::net::SocketAddr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::UdpSocket;
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_rapier3d::prelude::*;
+#[cfg(target_arch = "wasm32")]
+use net::WebSocketClient;
+#[cfg(not(target_arch = "wasm32"))]
+use net::{
+    AdminPlugin, SocketSet, StreamClient, StreamListener, ThreadedSocket, WebSocketClient,
+    WebSocketListener,
+};
+use net::{
+    ClientPlugin, DatagramSocket, Message, NetworkEvent, NetworkResource, NetworkTick,
+    NetworkTickSettings, ObjectId, PlayerId, RecordingSocket, ReplaySocket, ServerPlugin, Socket,
+    Transport,
+};
+
+use crate::authority::AuthorityPlugin;
+use crate::character::CharacterController;
+use crate::chat::ChatPlugin;
+use crate::lag_compensation::*;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::lobby::reload_level;
+use crate::lobby::MatchPlugin;
+use crate::player::*;
+
+const DEFAULT_HOST_ADDRESS: &str = "0.0.0.0:4567";
+/// How many ports after the host address the admin console listens, past the websocket listener.
+#[cfg(not(target_arch = "wasm32"))]
+const ADMIN_PORT_OFFSET: u16 = 2;
+/// Id of the player hosting a listen server.
+const HOST_PLAYER_ID: PlayerId = 0;
+/// How many times a second peers exchange messages, and the server sends the state of every
+/// player to its clients.
+const NETWORK_TICK_RATE: f64 = 30.;
+/// How far the predicted local body may drift from the server before it is snapped back.
+const CORRECTION_DISTANCE: f32 = 0.5;
+/// Portion of a small prediction error that is corrected each update.
+const CORRECTION_BLEND: f32 = 0.1;
+/// How far behind the latest server tick clients show remote state. State is applied as soon
+/// as it arrives, so there is no delay.
+const INTERPOLATION_DELAY_SECS: f32 = 0.;
+
+/// How this instance of the game takes part in a session.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// Single player, nothing is sent or received.
+    Offline,
+    /// Plays locally and simulates the bodies of connected clients.
+    Host(SocketAddr),
+    /// Sends input to a server and shows the state it replicates back.
+    Client(SocketAddr),
+}
+
+impl NetworkMode {
+    /// Reads the mode from the command line: `host [address]`, `join <address>` or nothing for
+    /// single player.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_args() -> Self {
+        let mut args = std::env::args().skip(1);
+        match args.next().as_deref() {
+            Some("host") => NetworkMode::Host(
+                args.next()
+                    .as_deref()
+                    .unwrap_or(DEFAULT_HOST_ADDRESS)
+                    .parse()
+                    .expect("could not parse host address"),
+            ),
+            Some("join") => NetworkMode::Client(
+                args.next()
+                    .expect("usage: short-game join <address>")
+                    .parse()
+                    .expect("could not parse server address"),
+            ),
+            _ => NetworkMode::Offline,
+        }
+    }
+
+    /// Reads the mode from the page's query string: `?join=<address>` joins the host whose
+    /// websocket listener is at `address`, anything else plays single player.
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_args() -> Self {
+        let query = web_sys::window()
+            .and_then(|window| window.location().search().ok())
+            .unwrap_or_default();
+        query
+            .trim_start_matches('?')
+            .split('&')
+            .find_map(|param| param.strip_prefix("join="))
+            .and_then(|addr| addr.parse().ok())
+            .map_or(NetworkMode::Offline, NetworkMode::Client)
+    }
+}
+
+/// Identifies the player an `FPSBody` or remote avatar belongs to.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetPlayer(pub PlayerId);
+
+/// Marks the kinematic stand-in a client shows for another player's body.
+#[derive(Component)]
+pub struct RemoteAvatar;
+
+/// Identifies an object that is shared between peers. Every peer spawns these in `setup`, so the
+/// ids have to be assigned the same way everywhere.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetObject(pub ObjectId);
+
+/// Keeps track of which entity and connection belongs to which player.
+#[derive(Resource, Default)]
+pub struct NetPlayers {
+    /// Id of the local player, once known.
+    pub local: Option<PlayerId>,
+    pub entities: HashMap<PlayerId, Entity>,
+    pub addresses: HashMap<SocketAddr, PlayerId>,
+    next_id: PlayerId,
+}
+
+impl NetPlayers {
+    /// Returns the entity of the player connected from `addr`.
+    pub fn entity_for(&self, addr: &SocketAddr) -> Option<Entity> {
+        self.addresses
+            .get(addr)
+            .and_then(|id| self.entities.get(id))
+            .copied()
+    }
+
+    /// Returns the address the player with `id` is connected from.
+    pub fn address_of(&self, id: PlayerId) -> Option<SocketAddr> {
+        self.addresses
+            .iter()
+            .find(|(_, player)| **player == id)
+            .map(|(addr, _)| *addr)
+    }
+}
+
+/// On the server, the number of network ticks so far. On a client, the latest tick received
+/// from the server.
+#[derive(Resource, Default)]
+pub struct ServerTick(pub u64);
+
+/// Label for the game's networking systems.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, SystemLabel)]
+pub enum MultiplayerSystem {
+    /// Advances the `ServerTick` along with the `NetworkTick`.
+    Tick,
+    /// Applies grab requests or grab state received from other peers.
+    Grab,
+    /// Sends the state of players to clients.
+    Replicate,
+}
+
+/// Spawns a body for every connected client and replicates player state between peers
+pub struct MultiplayerPlugin {
+    pub mode: NetworkMode,
+}
+
+impl Plugin for MultiplayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.mode)
+            .init_resource::<NetPlayers>()
+            .insert_resource(NetworkTickSettings {
+                rate: NETWORK_TICK_RATE,
+                ..default()
+            })
+            .init_resource::<ServerTick>()
+            .add_plugin(AuthorityPlugin { mode: self.mode })
+            .add_plugin(ChatPlugin { mode: self.mode })
+            .add_plugin(MatchPlugin { mode: self.mode })
+            .add_system(release_orphaned_grabs.before(PlayerSystem::Grab));
+
+        match self.mode {
+            NetworkMode::Offline => {}
+            NetworkMode::Host(addr) => {
+                add_admin_console(app, addr);
+                app.insert_resource(host_socket(addr))
+                    .add_plugin(ServerPlugin)
+                    .add_plugin(LagCompensationPlugin)
+                    .add_system(register_host_player)
+                    .add_system(advance_server_tick.label(MultiplayerSystem::Tick))
+                    .add_system(server_connection_handler.before(PlayerSystem::Move))
+                    .add_system(apply_remote_look.before(PlayerSystem::Move))
+                    .add_system(
+                        server_grab_handler
+                            .label(MultiplayerSystem::Grab)
+                            .after(PlayerSystem::Grab),
+                    )
+                    .add_system(broadcast_host_grabs.after(PlayerSystem::Grab))
+                    .add_system(
+                        replicate_players
+                            .label(MultiplayerSystem::Replicate)
+                            .after(MultiplayerSystem::Tick)
+                            .after(PlayerSystem::Move),
+                    );
+            }
+            NetworkMode::Client(addr) => {
+                info!("Joining {}", addr);
+
+                app.insert_resource(client_socket(addr))
+                    .add_plugin(ClientPlugin)
+                    .add_system(client_message_handler)
+                    .add_system(
+                        client_grab_handler
+                            .label(MultiplayerSystem::Grab)
+                            .after(PlayerSystem::Grab),
+                    )
+                    .add_system(send_grab_requests.after(PlayerSystem::Grab))
+                    .add_system(
+                        send_local_command
+                            .after(PlayerSystem::Input)
+                            .before(PlayerSystem::Move),
+                    );
+            }
+        }
+    }
+}
+
+/// Listens for UDP and TCP clients on `addr` and for WebSocket clients, such as the browser
+/// build, on the port after it.
+#[cfg(not(target_arch = "wasm32"))]
+fn host_socket(addr: SocketAddr) -> Socket {
+    let udp = UdpSocket::bind(addr).expect("could not bind socket");
+    udp.set_nonblocking(true)
+        .expect("could not set socket to be nonblocking");
+    let tcp = StreamListener::bind(addr).expect("could not bind tcp listener");
+    let websocket_addr = port_after(addr, 1)
+        .expect("the host port is too high to listen for websockets on the port after it");
+    let websocket =
+        WebSocketListener::bind(websocket_addr).expect("could not bind websocket listener");
+    info!("Hosting on {} (websockets on {})", addr, websocket_addr);
+
+    open_socket(SocketSet::new(vec![
+        Box::new(udp),
+        Box::new(tcp),
+        Box::new(websocket),
+    ]))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn host_socket(_addr: SocketAddr) -> Socket {
+    unreachable!("the browser build can't host")
+}
+
+/// Lets admins manage the session from the host machine, on the port two after `addr`, when
+/// `NET_ADMIN_TOKEN` sets the token they have to authenticate with. Reloading rebuilds the level
+/// and starts the match over from the lobby.
+#[cfg(not(target_arch = "wasm32"))]
+fn add_admin_console(app: &mut App, addr: SocketAddr) {
+    match env::var("NET_ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => {
+            let admin_addr = match port_after(addr, ADMIN_PORT_OFFSET) {
+                Some(admin_addr) => SocketAddr::new([127, 0, 0, 1].into(), admin_addr.port()),
+                None => {
+                    warn!(
+                        "no admin console, there is no port {} after {}",
+                        ADMIN_PORT_OFFSET, addr
+                    );
+                    return;
+                }
+            };
+            info!("Admin console listening on {}", admin_addr);
+            app.add_plugin(AdminPlugin {
+                addr: admin_addr,
+                token,
+            })
+            .add_system(reload_level);
+        }
+        _ => info!("Set NET_ADMIN_TOKEN to enable the admin console"),
+    }
+}
+
+/// `addr` with the port `offset` after its own, `None` past the last port.
+#[cfg(not(target_arch = "wasm32"))]
+fn port_after(addr: SocketAddr, offset: u16) -> Option<SocketAddr> {
+    let port = addr.port().checked_add(offset)?;
+    Some(SocketAddr::new(addr.ip(), port))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn add_admin_console(_app: &mut App, _addr: SocketAddr) {}
+
+/// Joins over UDP, unless `NET_TRANSPORT` asks for `tcp` or `websocket` on networks that block
+/// it. A host's websocket listener is on the port after its address.
+#[cfg(not(target_arch = "wasm32"))]
+fn client_socket(addr: SocketAddr) -> Socket {
+    match env::var("NET_TRANSPORT").as_deref() {
+        Ok("tcp") => open_socket(StreamClient::connect(addr).expect("could not connect to server")),
+        Ok("websocket") => {
+            open_socket(WebSocketClient::connect(addr).expect("could not connect to server"))
+        }
+        _ => {
+            let socket = UdpSocket::bind("0.0.0.0:0").expect("could not bind socket");
+            socket.connect(addr).expect("could not connect to server");
+            socket
+                .set_nonblocking(true)
+                .expect("could not set socket to be nonblocking");
+            open_socket(socket)
+        }
+    }
+}
+
+/// Browsers can't send UDP, so the browser build joins a host's websocket listener instead.
+#[cfg(target_arch = "wasm32")]
+fn client_socket(addr: SocketAddr) -> Socket {
+    open_socket(WebSocketClient::connect(addr).expect("could not open websocket"))
+}
+
+/// Wraps the socket in a recorder when `NET_CAPTURE` names a capture file to write, or swaps it
+/// for a replay of the capture named by `NET_REPLAY`. Both sit outside the I/O thread, so they
+/// see the packets in the same network ticks as the game does.
+fn open_socket(socket: impl DatagramSocket) -> Socket {
+    if let Some(path) = env::var_os("NET_REPLAY") {
+        info!("Replaying packets from {:?}", path);
+        return Socket::new(ReplaySocket::open(&path).expect("could not read capture file"));
+    }
+    match env::var_os("NET_CAPTURE") {
+        Some(path) => {
+            info!("Capturing packets to {:?}", path);
+            Socket::new(
+                RecordingSocket::create(io_thread(socket), &path)
+                    .expect("could not create capture file"),
+            )
+        }
+        None => Socket::new(io_thread(socket)),
+    }
+}
+
+/// Sends and receives on a thread of its own, so a long frame doesn't hold packets up.
+#[cfg(not(target_arch = "wasm32"))]
+fn io_thread(socket: impl DatagramSocket) -> impl DatagramSocket {
+    ThreadedSocket::spawn(socket).expect("could not spawn network thread")
+}
+
+#[cfg(target_arch = "wasm32")]
+fn io_thread(socket: impl DatagramSocket) -> impl DatagramSocket {
+    socket
+}
+
+/// Gives the host's own body a player id so it is replicated like everyone else's
+fn register_host_player(
+    mut commands: Commands,
+    mut players: ResMut<NetPlayers>,
+    query: Query<Entity, (With<LocalPlayer>, Without<NetPlayer>)>,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).insert(NetPlayer(HOST_PLAYER_ID));
+        players.entities.insert(HOST_PLAYER_ID, entity);
+        players.local = Some(HOST_PLAYER_ID);
+        players.next_id = players.next_id.max(HOST_PLAYER_ID + 1);
+    }
+}
+
+fn server_connection_handler(
+    mut commands: Commands,
+    mut events: EventReader<NetworkEvent>,
+    mut transport: ResMut<Transport>,
+    mut players: ResMut<NetPlayers>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    net: Res<NetworkResource>,
+    bodies: Query<(&NetPlayer, &Transform)>,
+    mut inputs: Query<&mut ControlInput>,
+) {
+    for event in events.iter() {
+        match event {
+            NetworkEvent::Connected(addr) => {
+                let id = players.next_id;
+                players.next_id += 1;
+
+                let body = spawn_player_body(&mut commands, SPAWN_POINT);
+                commands.entity(body).insert(NetPlayer(id));
+                spawn_avatar_mesh(&mut commands, &mut meshes, &mut materials, body);
+                players.entities.insert(id, body);
+                players.addresses.insert(*addr, id);
+                info!("{}: joined as player {}", addr, id);
+
+                // reliably, or a client that misses its welcome takes its own body for someone
+                // else's
+                transport.send_reliable_to(*addr, Message::Welcome(id));
+                for (player, transform) in bodies.iter() {
+                    transport.send_reliable_to(
+                        *addr,
+                        Message::PlayerJoined(player.0, transform.translation),
+                    );
+                }
+                transport.broadcast_reliable(
+                    net.connections.keys(),
+                    Message::PlayerJoined(id, SPAWN_POINT),
+                );
+            }
+            NetworkEvent::Disconnected(addr) => {
+                if let Some(id) = players.addresses.remove(addr) {
+                    if let Some(body) = players.entities.remove(&id) {
+                        commands.entity(body).despawn_recursive();
+                    }
+                    info!("{}: player {} left", addr, id);
+                    transport.broadcast_reliable(net.connections.keys(), Message::PlayerLeft(id));
+                }
+            }
+            NetworkEvent::Message(addr, Message::Command(command)) => {
+                if let Some(mut input) = players
+                    .entity_for(addr)
+                    .and_then(|body| inputs.get_mut(body).ok())
+                {
+                    // a jump may arrive in a command that is overwritten before it is simulated
+                    let jump = input.0.jump || command.jump;
+                    input.0 = *command;
+                    input.0.jump = jump;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Turns the bodies of remote players to face where their clients are looking
+fn apply_remote_look(
+    mut query: Query<(&mut Transform, &ControlInput), (With<FPSBody>, Without<LocalPlayer>)>,
+) {
+    for (mut transform, input) in query.iter_mut() {
+        transform.rotation = Quat::from_axis_angle(Vec3::Y, input.0.yaw);
+    }
+}
+
+fn advance_server_tick(network_tick: Res<NetworkTick>, mut tick: ResMut<ServerTick>) {
+    tick.0 = network_tick.tick;
+}
+
+fn replicate_players(
+    network_tick: Res<NetworkTick>,
+    tick: Res<ServerTick>,
+    net: Res<NetworkResource>,
+    mut transport: ResMut<Transport>,
+    bodies: Query<(&NetPlayer, &Transform, &CharacterController, &ControlInput)>,
+    player_ids: Query<&NetPlayer>,
+    objects: Query<(&NetObject, &Grabbable)>,
+) {
+    if !network_tick.just_ticked() {
+        return;
+    }
+
+    transport.broadcast(net.connections.keys(), Message::Tick(tick.0));
+
+    for (player, transform, controller, input) in bodies.iter() {
+        transport.broadcast(
+            net.connections.keys(),
+            Message::PlayerState {
+                id: player.0,
+                translation: transform.translation,
+                velocity: controller.velocity,
+                yaw: input.0.yaw,
+            },
+        );
+    }
+
+    // repeat who holds what, in case a GrabState was lost
+    for (object, grabbable) in objects.iter() {
+        if let Some(holder) = grabbable.holder.and_then(|h| player_ids.get(h).ok()) {
+            transport.broadcast(
+                net.connections.keys(),
+                Message::GrabState {
+                    object: object.0,
+                    holder: Some(holder.0),
+                },
+            );
+        }
+    }
+}
+
+fn send_local_command(
+    network_tick: Res<NetworkTick>,
+    tick: Res<ServerTick>,
+    mut jumped: Local<bool>,
+    mut transport: ResMut<Transport>,
+    query: Query<&ControlInput, With<LocalPlayer>>,
+) {
+    for input in query.iter() {
+        // player_move consumes a jump the same frame, hold on to it until the next tick
+        *jumped |= input.0.jump;
+        if !network_tick.just_ticked() {
+            continue;
+        }
+
+        // tells the server what this client was looking at, see `PoseHistory::view_time`
+        let mut command = input.0;
+        command.jump = std::mem::take(&mut *jumped);
+        command.view_tick = tick.0;
+        command.interpolation_delay = INTERPOLATION_DELAY_SECS;
+        transport.send(Message::Command(command));
+    }
+}
+
+fn client_message_handler(
+    mut commands: Commands,
+    mut events: EventReader<NetworkEvent>,
+    mut exit: EventWriter<AppExit>,
+    mut tick: ResMut<ServerTick>,
+    mut players: ResMut<NetPlayers>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut local: Query<(Entity, &mut Transform, &mut CharacterController), With<LocalPlayer>>,
+    mut avatars: Query<&mut Transform, (With<RemoteAvatar>, Without<LocalPlayer>)>,
+) {
+    for event in events.iter() {
+        let message = match event {
+            NetworkEvent::Message(_, message) => message,
+            NetworkEvent::Rejected(addr, reason) => {
+                error!("could not join {}: {}", addr, reason);
+                exit.send(AppExit);
+                continue;
+            }
+            _ => continue,
+        };
+
+        match message {
+            Message::Kicked(reason) => {
+                error!("kicked from the server: {}", reason);
+                exit.send(AppExit);
+            }
+            Message::Tick(server_tick) => {
+                tick.0 = tick.0.max(*server_tick);
+            }
+            Message::Welcome(id) => {
+                info!("joined as player {}", id);
+                players.local = Some(*id);
+                for (entity, _, _) in local.iter() {
+                    commands.entity(entity).insert(NetPlayer(*id));
+                    players.entities.insert(*id, entity);
+                }
+            }
+            Message::PlayerJoined(id, translation) => {
+                if players.local != Some(*id) && !players.entities.contains_key(id) {
+                    let avatar = spawn_remote_avatar(
+                        &mut commands,
+                        &mut meshes,
+                        &mut materials,
+                        *id,
+                        *translation,
+                    );
+                    players.entities.insert(*id, avatar);
+                }
+            }
+            Message::PlayerLeft(id) => {
+                if players.local != Some(*id) {
+                    if let Some(avatar) = players.entities.remove(id) {
+                        commands.entity(avatar).despawn_recursive();
+                    }
+                }
+            }
+            Message::PlayerState {
+                id,
+                translation,
+                velocity,
+                yaw,
+            } => {
+                if players.local == Some(*id) {
+                    // the local body is predicted, only correct it when it drifts away
+                    for (_, mut transform, mut controller) in local.iter_mut() {
+                        let error = *translation - transform.translation;
+                        if error.length() > CORRECTION_DISTANCE {
+                            transform.translation = *translation;
+                            controller.velocity = *velocity;
+                        } else {
+                            transform.translation += error * CORRECTION_BLEND;
+                        }
+                    }
+                } else if let Some(avatar) = players.entities.get(id) {
+                    if let Ok(mut transform) = avatars.get_mut(*avatar) {
+                        transform.translation = *translation;
+                        transform.rotation = Quat::from_axis_angle(Vec3::Y, *yaw);
+                    }
+                } else {
+                    // the PlayerJoined for this player was lost
+                    let avatar = spawn_remote_avatar(
+                        &mut commands,
+                        &mut meshes,
+                        &mut materials,
+                        *id,
+                        *translation,
+                    );
+                    players.entities.insert(*id, avatar);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn spawn_remote_avatar(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    id: PlayerId,
+    translation: Vec3,
+) -> Entity {
+    let avatar = commands
+        .spawn_bundle(TransformBundle::from(Transform::from_translation(
+            translation,
+        )))
+        .insert(VisibilityBundle::default())
+        .insert(RigidBody::KinematicPositionBased)
+        .insert(Collider::capsule_y(1., 0.5))
+        .insert(RemoteAvatar)
+        .insert(NetPlayer(id))
+        .id();
+    spawn_avatar_mesh(commands, meshes, materials, avatar);
+    avatar
+}
+
+/// Lets go of anything held by a body that no longer exists, e.g. after its player left
+fn release_orphaned_grabs(
+    bodies: Query<(), Or<(With<FPSBody>, With<RemoteAvatar>)>>,
+    mut state: ResMut<PlayerState>,
+    mut grabbables: Query<(
+        Entity,
+        &mut ExternalForce,
+        &mut Grabbable,
+        &mut GravityScale,
+    )>,
+) {
+    for (entity, mut force, mut grabbable, mut gravity) in grabbables.iter_mut() {
+        if let Some(holder) = grabbable.holder {
+            if bodies.get(holder).is_err() {
+                release_grabbable(&mut grabbable, &mut force, &mut gravity);
+                if state.grabbing == Some(entity) {
+                    state.grabbing = None;
+                }
+            }
+        }
+    }
+}
+
+/// Validates grab and release requests from clients. A grab is only granted when the object
+/// is free, the player holds nothing else and the server's own ray from the player's eyes hits it.
+/// Thrown objects get the client's impulse, as long as it doesn't throw them too fast.
+fn server_grab_handler(
+    mut events: EventReader<NetworkEvent>,
+    mut transport: ResMut<Transport>,
+    net: Res<NetworkResource>,
+    players: Res<NetPlayers>,
+    history: Res<PoseHistory>,
+    mut rapier_context: ResMut<RapierContext>,
+    bodies: Query<(&Transform, &ControlInput), (With<FPSBody>, Without<Grabbable>)>,
+    player_ids: Query<&NetPlayer>,
+    mut grabbables: Query<(
+        Entity,
+        &NetObject,
+        &mut ExternalForce,
+        &mut Grabbable,
+        &mut GravityScale,
+        &mut ExternalImpulse,
+        &ReadMassProperties,
+    )>,
+) {
+    for event in events.iter() {
+        let (addr, message) = match event {
+            NetworkEvent::Message(addr, message) => (addr, message),
+            _ => continue,
+        };
+        let (id, body) = match players
+            .addresses
+            .get(addr)
+            .and_then(|id| players.entities.get(id).map(|body| (*id, *body)))
+        {
+            Some(player) => player,
+            None => continue,
+        };
+
+        match message {
+            Message::Grab(object) => {
+                let target = match grabbables.iter().find(|(_, o, ..)| o.0 == *object) {
+                    Some((entity, ..)) => entity,
+                    None => continue,
+                };
+
+                let mut free: HashMap<Entity, bool> = HashMap::new();
+                let mut already_holding = false;
+                for (entity, _, _, grabbable, _, _, mass) in grabbables.iter() {
+                    free.insert(entity, grabbable.is_free(mass));
+                    already_holding |= grabbable.holder == Some(body);
+                }
+                // check against the world as the client saw it when it asked
+                let hit = bodies.get(body).ok().and_then(|(transform, input)| {
+                    let eye = eye_transform(transform, &input.0);
+                    let view_time =
+                        history.view_time(input.0.view_tick, input.0.interpolation_delay);
+                    rewind(
+                        &mut rapier_context,
+                        &history,
+                        view_time,
+                        Some(body),
+                        |context| {
+                            cast_grab_ray(context, &eye, &free, &[body]).map(|(entity, _)| entity)
+                        },
+                    )
+                });
+
+                let (_, _, _, mut grabbable, mut gravity, ..) = grabbables.get_mut(target).unwrap();
+                if !already_holding && hit == Some(target) {
+                    hold_grabbable(&mut grabbable, &mut gravity, body);
+                    transport.broadcast_reliable(
+                        net.connections.keys(),
+                        Message::GrabState {
+                            object: *object,
+                            holder: Some(id),
+                        },
+                    );
+                } else {
+                    debug!("{}: denied grab of object {}", addr, object);
+                    transport.send_reliable_to(
+                        *addr,
+                        Message::GrabState {
+                            object: *object,
+                            holder: grabbable
+                                .holder
+                                .and_then(|h| player_ids.get(h).ok())
+                                .map(|p| p.0),
+                        },
+                    );
+                }
+            }
+            Message::Release(object) | Message::Throw { object, .. } => {
+                if let Some((_, _, mut force, mut grabbable, mut gravity, mut impulse, mass)) =
+                    grabbables
+                        .iter_mut()
+                        .find(|(_, o, _, g, ..)| o.0 == *object && g.holder == Some(body))
+                {
+                    release_grabbable(&mut grabbable, &mut force, &mut gravity);
+                    if let Message::Throw {
+                        impulse: thrown, ..
+                    } = message
+                    {
+                        if thrown.is_finite() {
+                            impulse.impulse +=
+                                thrown.clamp_length_max(MAX_THROW_SPEED * mass.0.mass);
+                        }
+                    }
+                    transport.broadcast_reliable(
+                        net.connections.keys(),
+                        Message::GrabState {
+                            object: *object,
+                            holder: None,
+                        },
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Tells clients about objects the host picks up or drops
+fn broadcast_host_grabs(
+    mut grab_events: EventReader<GrabEvent>,
+    mut transport: ResMut<Transport>,
+    net: Res<NetworkResource>,
+    players: Res<NetPlayers>,
+    objects: Query<&NetObject>,
+) {
+    for event in grab_events.iter() {
+        let (entity, holder) = match event {
+            GrabEvent::Grabbed(entity) => (entity, players.local),
+            GrabEvent::Released(entity) | GrabEvent::Thrown(entity, _) => (entity, None),
+        };
+        if let Ok(object) = objects.get(*entity) {
+            transport.broadcast_reliable(
+                net.connections.keys(),
+                Message::GrabState {
+                    object: object.0,
+                    holder,
+                },
+            );
+        }
+    }
+}
+
+/// Forwards the local player's grabs to the server, which confirms or denies them
+fn send_grab_requests(
+    mut grab_events: EventReader<GrabEvent>,
+    mut transport: ResMut<Transport>,
+    objects: Query<&NetObject>,
+) {
+    for event in grab_events.iter() {
+        match event {
+            GrabEvent::Grabbed(entity) => {
+                if let Ok(object) = objects.get(*entity) {
+                    transport.send_reliable(Message::Grab(object.0));
+                }
+            }
+            GrabEvent::Released(entity) => {
+                if let Ok(object) = objects.get(*entity) {
+                    transport.send_reliable(Message::Release(object.0));
+                }
+            }
+            GrabEvent::Thrown(entity, impulse) => {
+                if let Ok(object) = objects.get(*entity) {
+                    transport.send_reliable(Message::Throw {
+                        object: object.0,
+                        impulse: *impulse,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Applies the server's view of who holds what, rolling back an optimistic grab it denied
+fn client_grab_handler(
+    mut events: EventReader<NetworkEvent>,
+    mut state: ResMut<PlayerState>,
+    players: Res<NetPlayers>,
+    mut grabbables: Query<(
+        Entity,
+        &NetObject,
+        &mut ExternalForce,
+        &mut Grabbable,
+        &mut GravityScale,
+    )>,
+) {
+    for event in events.iter() {
+        let (object, holder) = match event {
+            NetworkEvent::Message(_, Message::GrabState { object, holder }) => (object, holder),
+            _ => continue,
+        };
+        let (entity, _, mut force, mut grabbable, mut gravity) =
+            match grabbables.iter_mut().find(|(_, o, ..)| o.0 == *object) {
+                Some(grabbable) => grabbable,
+                None => continue,
+            };
+
+        if holder.is_some() && *holder == players.local {
+            // already let go locally, the server will catch up with the release
+            continue;
+        }
+        if state.grabbing == Some(entity) {
+            info!("grab of object {} was denied", object);
+            state.grabbing = None;
+        }
+        match holder.and_then(|id| players.entities.get(&id)) {
+            Some(body) => hold_grabbable(&mut grabbable, &mut gravity, *body),
+            None => release_grabbable(&mut grabbable, &mut force, &mut gravity),
+        }
+    }
+}
@@ -1,14 +1,17 @@
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
 use bevy::window::CursorGrabMode;
-use inline_tweak::*;
 
 use bevy::ecs::system::Command;
-use bevy::input::mouse::MouseButton;
 use bevy::input::mouse::MouseButtonInput;
-use bevy::input::mouse::MouseMotion;
 use bevy::prelude::*;
 use bevy::time::FixedTimestep;
 use bevy_rapier3d::prelude::*;
+use net::{MatchPhase, PlayerCommand};
+use serde::{Deserialize, Serialize};
+
+use crate::actions::*;
+use crate::character::*;
+use crate::interact::{Focus, Inventory};
 
 /// Keeps track of mouse motion events, pitch, and yaw
 #[derive(Default, Resource)]
@@ -17,20 +20,68 @@ struct InputState {
     yaw: f32,
 }
 
-/// Mouse sensitivity and movement speed
-#[derive(Resource)]
+/// Mouse sensitivity and movement speed, kept in the player's `Settings`
+#[derive(Resource, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct MovementSettings {
     pub sensitivity: f32,
     pub max_speed: f32,
+    /// How quickly a body on the ground gets up to speed or stops, in m/s². It only gets a
+    /// portion of it in the air.
     pub acceleration: f32,
-    pub max_acceleration_force: f32,
+    /// Strongest force a body pushes the dynamic bodies it walks into with.
+    pub push_strength: f32,
     pub fov: f32,
+    /// Upwards speed a jump starts with.
+    pub jump_speed: f32,
+    /// Steepest slope a body can walk up, in degrees.
+    pub max_slope_angle: f32,
+    /// Tallest ledge a body steps up onto without jumping.
+    pub step_height: f32,
+    /// Furthest a body walking down a slope or off a step is pulled down to stay on the ground.
+    pub snap_distance: f32,
+    /// Steps, in degrees, held objects are rotated in. They rotate freely without any.
+    pub rotation_snap: Option<f32>,
+}
+
+/// Set while something else, such as the chat box or a menu, takes the keyboard. Movement, look
+/// and grab input are ignored meanwhile.
+#[derive(Default, Resource)]
+pub struct InputSuspended(HashSet<&'static str>);
+
+impl InputSuspended {
+    /// Suspends input on behalf of `owner`, or stops doing so.
+    pub fn set(&mut self, owner: &'static str, suspended: bool) {
+        if suspended {
+            self.0.insert(owner);
+        } else {
+            self.0.remove(owner);
+        }
+    }
+
+    /// Whether anything suspends input.
+    pub fn any(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// Whether anything but `owner` suspends input.
+    pub fn by_others(&self, owner: &'static str) -> bool {
+        self.0.iter().any(|other| *other != owner)
+    }
 }
 
 #[derive(Default, Resource)]
-struct PlayerState {
-    grounded: bool,
-    grabbing: Option<Entity>,
+pub struct PlayerState {
+    /// The object the local player is holding, possibly before the server confirmed it.
+    pub grabbing: Option<Entity>,
+    /// How long the throw action has been held down for, while holding something.
+    pub throw_charge: Option<f32>,
+    /// Time left before the local player can grab or throw again after a throw, in seconds.
+    throw_cooldown: f32,
+    /// Whether the local player is rotating what it holds rather than looking around.
+    pub rotating: bool,
+    /// Turning that didn't add up to a whole step yet while rotations snap, in degrees.
+    rotation_remainder: Vec2,
 }
 
 impl Default for MovementSettings {
@@ -38,9 +89,14 @@ impl Default for MovementSettings {
         Self {
             sensitivity: 0.00012,
             max_speed: 10.,
-            acceleration: 300.,
-            max_acceleration_force: 200.,
+            acceleration: 60.,
+            push_strength: 200.,
             fov: 90.,
+            jump_speed: 6.,
+            max_slope_angle: 45.,
+            step_height: 0.3,
+            snap_distance: 0.3,
+            rotation_snap: None,
         }
     }
 }
@@ -52,24 +108,250 @@ pub struct FPSCam;
 #[derive(Component)]
 pub struct FPSBody;
 
+/// Marks the `FPSBody` controlled by this instance of the game. Only this body gets the
+/// `FPSCam` and reads keyboard and mouse input.
+#[derive(Component)]
+pub struct LocalPlayer;
+
+/// The input driving an `FPSBody` this frame, either sampled locally or received from a client.
+#[derive(Component, Default)]
+pub struct ControlInput(pub PlayerCommand);
+
 #[derive(Component, Default)]
+pub struct Grounded(pub bool);
+
+/// Where new player bodies are placed.
+pub const SPAWN_POINT: Vec3 = Vec3::new(-2.0, 10.0, 5.0);
+/// Height of the eyes, and the `FPSCam`, above the body's origin.
+pub const EYE_HEIGHT: f32 = 0.95;
+/// How far away a player can grab things from.
+pub const GRAB_REACH: f32 = 3.;
+/// Portion of the `MovementSettings::acceleration` a body gets while in the air.
+const AIR_CONTROL: f32 = 0.3;
+/// Speed a fully charged throw gives an object, on top of what it carries over from the body.
+const THROW_SPEED: f32 = 12.;
+/// Seconds it takes to fully charge a throw.
+const THROW_CHARGE_SECS: f32 = 1.;
+/// Portion of a full throw that even a throw that wasn't charged at all gets.
+const MIN_THROW_CHARGE: f32 = 0.2;
+/// Portion of the thrower's velocity a thrown object carries over.
+const THROW_CARRY: f32 = 0.5;
+/// Seconds after a throw before the player can grab or throw again.
+const THROW_COOLDOWN_SECS: f32 = 0.5;
+/// How much one line of scrolling moves a held object closer or further away.
+const HOLD_DISTANCE_STEP: f32 = 0.25;
+/// How fast the hold actions move a held object closer or further away, in m/s.
+const HOLD_DISTANCE_SPEED: f32 = 2.;
+/// How quickly a held object that doesn't snap to an orientation stops swinging, per second.
+const HELD_ANGULAR_DRAG: f32 = 5.;
+/// How quickly a held object turns to the orientation it snaps to, per second.
+const SNAP_RATE: f32 = 10.;
+/// How far moving the mouse turns a held object while rotating it, in degrees per pixel.
+const ROTATE_SPEED: f32 = 0.3;
+/// Fastest a client may throw something, the server slows down anything thrown faster.
+pub const MAX_THROW_SPEED: f32 = 20.;
+
+/// Label for player systems that other plugins need to order against.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, SystemLabel)]
+pub enum PlayerSystem {
+    Input,
+    Move,
+    Grab,
+}
+
+/// Something players can pick up, with how it behaves while held.
+#[derive(Component)]
 pub struct Grabbable {
-    grabbed: bool,
+    /// The body holding this object.
+    pub holder: Option<Entity>,
+    /// How far in front of the holder's eyes it is held when picked up.
+    pub hold_distance: f32,
+    /// Closest and furthest the holder can move it with the mouse wheel.
+    pub min_hold_distance: f32,
+    pub max_hold_distance: f32,
+    /// Heaviest the object may be and still be picked up.
+    pub max_carry_mass: f32,
+    /// Orientation relative to the holder's facing the object turns to while held. Without one
+    /// it swings around the point it was grabbed by.
+    pub snap_rotation: Option<Quat>,
+    /// How far in front of the holder's eyes it is held right now.
+    pub distance: f32,
+    /// Orientation relative to the holder's facing it is held at right now, if any. Starts out
+    /// as the `snap_rotation` and is changed by rotating the object.
+    pub rotation: Option<Quat>,
+    /// The point it was grabbed by, relative to the object.
+    pub grab_point: Vec3,
+    /// How it is pulled in front of the holder.
+    pub hold: HoldMode,
+    /// The `GrabHand` it is attached to while held with a joint.
+    pub hand: Option<Entity>,
 }
 
-/// Grabs/ungrabs mouse cursor
-fn toggle_grab_cursor(window: &mut Window) {
-    if window.cursor_grab_mode() == CursorGrabMode::Locked {
-        window.set_cursor_grab_mode(CursorGrabMode::None);
+impl Default for Grabbable {
+    fn default() -> Self {
+        Self {
+            holder: None,
+            hold_distance: 3.,
+            min_hold_distance: 1.5,
+            max_hold_distance: 4.5,
+            max_carry_mass: 20.,
+            snap_rotation: None,
+            distance: 3.,
+            rotation: None,
+            grab_point: Vec3::ZERO,
+            hold: HoldMode::Force,
+            hand: None,
+        }
+    }
+}
+
+impl Grabbable {
+    /// Whether the object can be picked up, given its `mass`.
+    pub fn is_free(&self, mass: &ReadMassProperties) -> bool {
+        self.holder.is_none() && mass.0.mass <= self.max_carry_mass
+    }
+}
+
+/// How a held object is pulled in front of its holder.
+#[derive(Clone, Copy)]
+pub enum HoldMode {
+    /// By a force worked out every frame, with its velocity cancelled out.
+    Force,
+    /// By a spring joint to a kinematic `GrabHand`, which gives way when the object snags on
+    /// something.
+    Joint(JointHold),
+}
+
+/// Tuning of a `HoldMode::Joint`.
+#[derive(Clone, Copy)]
+pub struct JointHold {
+    /// How hard the spring pulls the object towards the hand, in m/s² for every metre apart.
+    pub stiffness: f32,
+    /// How strongly the spring resists the object moving or turning relative to the hand.
+    pub damping: f32,
+    /// Strongest force the spring pulls with before it breaks and the holder lets go, in newtons.
+    pub break_force: f32,
+}
+
+impl Default for JointHold {
+    fn default() -> Self {
+        Self {
+            stiffness: 200.,
+            damping: 20.,
+            break_force: 400.,
+        }
+    }
+}
+
+/// A kinematic body without a collider that follows a holder's view, which objects held with a
+/// `HoldMode::Joint` are attached to.
+#[derive(Component)]
+pub struct GrabHand;
+
+/// Sent when the local player picks something up or lets go of it.
+pub enum GrabEvent {
+    Grabbed(Entity),
+    Released(Entity),
+    /// Let go of with the given impulse.
+    Thrown(Entity, Vec3),
+}
+
+/// Where a body with the given input looks from and towards, matching its `FPSCam`.
+pub fn eye_transform(body: &Transform, input: &PlayerCommand) -> Transform {
+    Transform::from_translation(body.translation + Vec3::Y * EYE_HEIGHT).with_rotation(
+        Quat::from_axis_angle(Vec3::Y, input.yaw) * Quat::from_axis_angle(Vec3::X, input.pitch),
+    )
+}
+
+/// Casts the grab ray from `eye` and returns the grabbable it hits first in reach, and how far
+/// along the ray it was hit. `free` maps each grabbable to whether it can be picked up. Anything
+/// else in the way blocks the ray, apart from sensors and the colliders in `exclude`, such as
+/// the body it is cast from and what that holds.
+pub fn cast_grab_ray(
+    rapier_context: &RapierContext,
+    eye: &Transform,
+    free: &HashMap<Entity, bool>,
+    exclude: &[Entity],
+) -> Option<(Entity, f32)> {
+    let pred = &|v| !exclude.contains(&v);
+    let filter = QueryFilter::new().exclude_sensors().predicate(pred);
+
+    let (entity, distance) =
+        rapier_context.cast_ray(eye.translation, eye.forward(), GRAB_REACH, false, filter)?;
+    if free.get(&entity).copied().unwrap_or(false) {
+        Some((entity, distance))
     } else {
-        window.set_cursor_grab_mode(CursorGrabMode::Locked);
+        None
     }
-    // window.set_cursor_visibility(!window.cursor_visible());
 }
 
-/// Grabs the cursor when game first starts
-fn initial_grab_cursor(mut windows: ResMut<Windows>) {
-    toggle_grab_cursor(windows.get_primary_mut().unwrap());
+/// Attaches a grabbable to `holder` by its centre, the `grabbing` system then pulls it in front of
+/// its eyes.
+pub fn hold_grabbable(grabbable: &mut Grabbable, gravity: &mut GravityScale, holder: Entity) {
+    grabbable.holder = Some(holder);
+    grabbable.distance = grabbable.hold_distance;
+    grabbable.rotation = grabbable.snap_rotation;
+    grabbable.grab_point = Vec3::ZERO;
+    gravity.0 = 0.;
+}
+
+/// Lets go of a grabbable and gives it back its gravity.
+pub fn release_grabbable(
+    grabbable: &mut Grabbable,
+    force: &mut ExternalForce,
+    gravity: &mut GravityScale,
+) {
+    grabbable.holder = None;
+    *force = ExternalForce::default();
+    gravity.0 = GravityScale::default().0;
+}
+
+/// Impulse a throw charged for `charge` seconds along `direction` gives an object of `mass`,
+/// thrown by a body moving with `body_velocity`.
+fn throw_impulse(direction: Vec3, charge: f32, body_velocity: Vec3, mass: f32) -> Vec3 {
+    let charge = (charge / THROW_CHARGE_SECS).clamp(MIN_THROW_CHARGE, 1.);
+    (direction * THROW_SPEED * charge + body_velocity * THROW_CARRY) * mass
+}
+
+/// Spawns the physics body shared by local and remote players, without any camera or visuals.
+pub fn spawn_player_body(commands: &mut Commands, translation: Vec3) -> Entity {
+    commands
+        .spawn_bundle(TransformBundle::from(Transform::from_translation(
+            translation,
+        )))
+        .insert(VisibilityBundle::default())
+        .insert(RigidBody::KinematicPositionBased)
+        .insert(Collider::capsule_y(1., 0.5))
+        .insert(CharacterController::default())
+        .insert(FPSBody)
+        .insert(ControlInput::default())
+        .insert(Grounded::default())
+        .insert(Inventory::default())
+        .id()
+}
+
+/// Spawns the capsule mesh other players see in place of a body.
+pub fn spawn_avatar_mesh(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    parent: Entity,
+) {
+    let mesh = commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Capsule {
+                radius: 0.5,
+                depth: 2.,
+                ..Default::default()
+            })),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgb(0.3, 0.5, 0.8),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .id();
+    commands.entity(parent).add_child(mesh);
 }
 
 /// Spawns the `Camera3dBundle` to be controlled
@@ -82,7 +364,7 @@ fn setup_player(
 ) {
     let camera = commands
         .spawn_bundle(Camera3dBundle {
-            transform: Transform::from_xyz(0., 0.95, 0.).looking_at(Vec3::ZERO, Vec3::Y),
+            transform: Transform::from_xyz(0., EYE_HEIGHT, 0.).looking_at(Vec3::ZERO, Vec3::Y),
             projection: PerspectiveProjection {
                 fov: (settings.fov / 360.0) * (std::f32::consts::PI * 2.0),
                 ..Default::default()
@@ -93,21 +375,8 @@ fn setup_player(
         .insert(FPSCam)
         .id();
 
-    commands
-        .spawn_bundle(TransformBundle::from(Transform::from_xyz(-2.0, 10.0, 5.0)))
-        .insert(RigidBody::Dynamic)
-        .insert(LockedAxes::ROTATION_LOCKED)
-        .insert(Collider::capsule_y(1., 0.5))
-        .insert(Velocity::default())
-        .insert(Friction {
-            coefficient: 5.,
-            combine_rule: CoefficientCombineRule::Average,
-        })
-        .insert(ExternalImpulse::default())
-        .insert(ExternalForce::default())
-        .insert(ColliderMassProperties::Density(2.0))
-        .insert(FPSBody)
-        .add_child(camera);
+    let body = spawn_player_body(&mut commands, SPAWN_POINT);
+    commands.entity(body).insert(LocalPlayer).add_child(camera);
 
     let ground_size = 100.;
     let ground_height = 0.2;
@@ -131,294 +400,624 @@ fn setup_player(
         ));
 }
 
-fn detect_ground(
-    rapier_context: Res<RapierContext>,
-    mut commands: Commands,
+/// Turns what the local player holds in camera space instead of looking around while the rotate
+/// action is held down, in steps when the `MovementSettings` snap rotations
+fn rotate_held(
+    time: Res<Time>,
+    settings: Res<MovementSettings>,
+    bindings: Res<InputBindings>,
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
+    look: Res<InputState>,
     mut state: ResMut<PlayerState>,
-    query: Query<(Entity, &Transform), With<FPSBody>>,
+    mut grabbables: Query<(&Transform, &mut Grabbable)>,
 ) {
-    for (entity, transform) in query.iter() {
-        let ray_pos = transform.translation;
-        let ray_dir = Vec3::new(0.0, -1., 0.0);
-        let max_toi = 1.5;
-        let solid = false;
-        let filter = QueryFilter::default().exclude_rigid_body(entity);
-        let mut grounded = false;
-        if let Some((entity, toi)) =
-            rapier_context.cast_ray(ray_pos, ray_dir, max_toi, solid, filter)
-        {
-            // The first collider hit has the entity `entity` and it hit after
-            // the ray travelled a distance equal to `ray_dir * toi`.
-            grounded = true;
+    let held = state.grabbing.filter(|_| !suspended.any());
+    let (transform, mut grabbable) = match held.and_then(|held| grabbables.get_mut(held).ok()) {
+        Some(grabbable) => grabbable,
+        None => {
+            state.rotating = false;
+            return;
         }
-        state.grounded = grounded;
+    };
+
+    if actions.just_pressed(Action::ResetRotation) {
+        grabbable.rotation = Some(Quat::IDENTITY);
     }
+    state.rotating = actions.pressed(Action::Rotate);
+    if !state.rotating {
+        state.rotation_remainder = Vec2::ZERO;
+        return;
+    }
+
+    // in degrees, turning the side facing the player right and down
+    let delta = Vec2::new(
+        actions.axis(Action::LookLeft, Action::LookRight),
+        actions.axis(Action::LookUp, Action::LookDown),
+    ) * bindings.stick_look_speed
+        * time.delta_seconds()
+        + actions.mouse_motion * ROTATE_SPEED;
+    if delta == Vec2::ZERO && grabbable.rotation.is_some() {
+        return;
+    }
+
+    let yaw = Quat::from_axis_angle(Vec3::Y, look.yaw);
+    let rotation = grabbable
+        .rotation
+        .unwrap_or_else(|| yaw.inverse() * transform.rotation);
+    let turn = |degrees: Vec2| {
+        Quat::from_axis_angle(Vec3::Y, degrees.x.to_radians())
+            * Quat::from_axis_angle(Vec3::X, degrees.y.to_radians())
+    };
+
+    // around the camera's axes, which are pitched relative to the player's
+    let rotation = match settings.rotation_snap {
+        Some(step) => {
+            // whole steps around axes pitched by whole steps, so it stays on the grid
+            let total = state.rotation_remainder + delta;
+            let steps = (total / step).trunc();
+            state.rotation_remainder = total - steps * step;
+            let pitch = (look.pitch.to_degrees() / step).round() * step;
+            let pitch = Quat::from_axis_angle(Vec3::X, pitch.to_radians());
+            let turned =
+                pitch * turn(steps * step) * pitch.inverse() * snap_to_grid(rotation, step);
+            snap_to_grid(turned, step)
+        }
+        None => {
+            let pitch = Quat::from_axis_angle(Vec3::X, look.pitch);
+            pitch * turn(delta) * pitch.inverse() * rotation
+        }
+    };
+    grabbable.rotation = Some(rotation.normalize());
 }
 
-fn rotate_with_mouse(
-    state: Res<PlayerState>,
-    mut mouse_move: EventReader<MouseMotion>,
-    mut grabbables: Query<&mut Transform, With<Grabbable>>,
+/// Rounds each angle of `rotation` to a multiple of `step` degrees.
+fn snap_to_grid(rotation: Quat, step: f32) -> Quat {
+    let step = step.to_radians();
+    let (y, x, z) = rotation.to_euler(EulerRot::YXZ);
+    let round = |angle: f32| (angle / step).round() * step;
+    Quat::from_euler(EulerRot::YXZ, round(y), round(x), round(z))
+}
+
+/// Pulls every held object towards the point in front of its holder's eyes by the point it was
+/// grabbed by, which swings it around its centre of mass unless it snaps to an orientation.
+/// Objects held with a joint are left to it, and only their `GrabHand` is moved there.
+fn grabbing(
+    time: Res<Time>,
+    holders: Query<(&Transform, &ControlInput), (With<FPSBody>, Without<Grabbable>)>,
+    mut hands: Query<&mut Transform, (With<GrabHand>, Without<FPSBody>, Without<Grabbable>)>,
+    mut grabbables: Query<(
+        &Transform,
+        &ReadMassProperties,
+        &mut Velocity,
+        &mut ExternalForce,
+        &Grabbable,
+    )>,
 ) {
-    if let Some(ent) = state.grabbing {
-        if let Ok(mut trans) = grabbables.get_mut(ent) {
-            for ev in mouse_move.iter() {
-                let eu = trans.rotation.to_euler(EulerRot::XYZ);
-                //TODO: factor in player rotation
-                trans.rotation =
-                    Quat::from_euler(EulerRot::XYZ, 0., ev.delta.x / 100., -ev.delta.y / 100.)
-                        * trans.rotation;
+    for (trans, mass, mut vel, mut extforce, grabbable) in grabbables.iter_mut() {
+        if let Some((body, input)) = grabbable.holder.and_then(|h| holders.get(h).ok()) {
+            if let HoldMode::Joint(_) = grabbable.hold {
+                // the joint does the pulling, the hand only has to be in the right place
+                if let Some(mut hand) = grabbable.hand.and_then(|h| hands.get_mut(h).ok()) {
+                    let camtrans = eye_transform(body, &input.0);
+                    hand.translation =
+                        camtrans.translation + camtrans.forward() * grabbable.distance;
+                    // the joint turns the object to the hand, which leaves it be without a
+                    // rotation to hold it at
+                    hand.rotation = match grabbable.rotation {
+                        Some(rotation) => Quat::from_axis_angle(Vec3::Y, input.0.yaw) * rotation,
+                        None => trans.rotation,
+                    };
+                }
+                continue;
+            }
+
+            vel.linvel = Vec3::ZERO;
+            vel.angvel = match grabbable.rotation {
+                Some(rotation) => {
+                    let target = Quat::from_axis_angle(Vec3::Y, input.0.yaw) * rotation;
+                    let (axis, angle) = (target * trans.rotation.inverse()).to_axis_angle();
+                    // the short way around
+                    let angle = if angle > std::f32::consts::PI {
+                        angle - std::f32::consts::TAU
+                    } else {
+                        angle
+                    };
+                    axis * angle * SNAP_RATE
+                }
+                None => vel.angvel * (1. - HELD_ANGULAR_DRAG * time.delta_seconds()).max(0.),
+            };
+
+            let camtrans = eye_transform(body, &input.0);
+            let grablocation = camtrans.translation + (camtrans.forward() * grabbable.distance);
+            let point = trans.mul_vec3(grabbable.grab_point);
+            let direction = (grablocation - point).normalize();
+            let distance = grablocation.distance(point);
+
+            if (direction != Vec3::ZERO && distance > 0.005) {
+                //extforce.force = direction * (distance.sqrt().powf(10.) + distance * 1000.);
+                let press = ((((distance + 0.03) * 3.).log10() + 1.) * distance.sqrt());
+                let force = direction * ((press.abs() + press) / 2.) * 1500.;
+                let center = trans.mul_vec3(mass.0.local_center_of_mass);
+                *extforce = ExternalForce::at_point(force, point, center);
+                //println!("{:?}", extforce.force);
+            } else {
+                *extforce = ExternalForce::default();
             }
+            //trans.translation = camtrans.translation + (camtrans.forward() * 1.5);
         }
     }
 }
 
-fn grabbing(
-    state: Res<PlayerState>,
-    camera: Query<&GlobalTransform, With<FPSCam>>,
-    mut grabbables: Query<
-        (
-            Entity,
-            &mut Transform,
-            &mut Velocity,
-            &mut ExternalForce,
-            &mut Grabbable,
-            &mut GravityScale,
-        ),
-        With<Grabbable>,
-    >,
+/// Attaches objects held with a `HoldMode::Joint` to a new `GrabHand` where they were grabbed,
+/// and removes the hand and joint again once they are let go of
+fn sync_grab_joints(
+    mut commands: Commands,
+    mut grabbables: Query<(Entity, &Transform, &mut Grabbable)>,
 ) {
-    if let Some(grabbedEnt) = state.grabbing {
-        if let Ok((_, mut trans, mut vel, mut extforce, _, mut gscale)) =
-            grabbables.get_mut(grabbedEnt)
-        {
-            for _global_transform in camera.iter() {
-                *vel = Velocity::zero();
-                let camtrans = _global_transform.compute_transform();
-                let grablocation = camtrans.translation + (camtrans.forward() * 3.0);
-                let direction = (grablocation - trans.translation).normalize();
-                let distance = grablocation.distance(trans.translation);
-
-                if (direction != Vec3::ZERO && distance > 0.005) {
-                    //extforce.force = direction * (distance.sqrt().powf(10.) + distance * 1000.);
-                    let press = ((((distance + 0.03) * 3.).log10() + 1.) * distance.sqrt());
-                    extforce.force = direction * ((press.abs() + press) / 2.) * 1500.;
-                    //println!("{:?}", extforce.force);
-                } else {
-                    extforce.force = Vec3::ZERO;
-                }
-                //trans.translation = camtrans.translation + (camtrans.forward() * 1.5);
+    for (entity, trans, mut grabbable) in grabbables.iter_mut() {
+        let settings = match (grabbable.hold, grabbable.holder, grabbable.hand) {
+            (HoldMode::Joint(settings), Some(_), None) => settings,
+            (_, None, Some(hand)) => {
+                commands.entity(hand).despawn();
+                commands.entity(entity).remove::<ImpulseJoint>();
+                grabbable.hand = None;
+                continue;
             }
-        }
+            _ => continue,
+        };
+
+        let hand = commands
+            .spawn_bundle(TransformBundle::from(
+                Transform::from_translation(trans.mul_vec3(grabbable.grab_point))
+                    .with_rotation(trans.rotation),
+            ))
+            .insert(RigidBody::KinematicPositionBased)
+            .insert(GrabHand)
+            .id();
+        let joint = grab_joint(&settings, grabbable.grab_point);
+        commands
+            .entity(entity)
+            .insert(ImpulseJoint::new(hand, joint));
+        grabbable.hand = Some(hand);
+    }
+}
+
+/// A joint that pulls `grab_point` on an object towards its hand, and turns the object to the
+/// hand's orientation, with springs.
+fn grab_joint(settings: &JointHold, grab_point: Vec3) -> GenericJoint {
+    let mut joint = GenericJointBuilder::new(JointAxesMask::empty()).local_anchor2(grab_point);
+    for axis in [JointAxis::X, JointAxis::Y, JointAxis::Z] {
+        joint = joint
+            .motor_position(axis, 0., settings.stiffness, settings.damping)
+            // pulling any harder could drag it through walls
+            .motor_max_force(axis, settings.break_force);
+    }
+    for axis in [JointAxis::AngX, JointAxis::AngY, JointAxis::AngZ] {
+        joint = joint.motor_position(axis, 0., settings.stiffness, settings.damping);
+    }
+    joint.build()
+}
+
+/// Lets go of what the local player holds with a joint once it's pulled apart from its hand
+/// hard enough to break the joint, such as when it snags on something
+fn break_grab_joints(
+    mut state: ResMut<PlayerState>,
+    mut grab_events: EventWriter<GrabEvent>,
+    hands: Query<&Transform, With<GrabHand>>,
+    mut grabbables: Query<(
+        &Transform,
+        &ReadMassProperties,
+        &mut ExternalForce,
+        &mut Grabbable,
+        &mut GravityScale,
+    )>,
+) {
+    let held = match state.grabbing {
+        Some(held) => held,
+        None => return,
+    };
+    let (trans, mass, mut force, mut grabbable, mut gravity) = match grabbables.get_mut(held) {
+        Ok(grabbable) => grabbable,
+        Err(_) => return,
+    };
+    let (settings, hand) = match (
+        grabbable.hold,
+        grabbable.hand.and_then(|h| hands.get(h).ok()),
+    ) {
+        (HoldMode::Joint(settings), Some(hand)) => (settings, hand),
+        _ => return,
+    };
+
+    let stretch = hand
+        .translation
+        .distance(trans.mul_vec3(grabbable.grab_point));
+    if mass.0.mass * settings.stiffness * stretch > settings.break_force {
+        release_grabbable(&mut grabbable, &mut force, &mut gravity);
+        state.grabbing = None;
+        grab_events.send(GrabEvent::Released(held));
+    }
+}
+
+/// Moves what the local player holds closer or further away with the mouse wheel and the hold
+/// actions
+fn adjust_hold_distance(
+    time: Res<Time>,
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
+    state: Res<PlayerState>,
+    mut grabbables: Query<&mut Grabbable>,
+) {
+    if suspended.any() {
+        return;
+    }
+    let change = actions.scroll * HOLD_DISTANCE_STEP
+        + actions.axis(Action::HoldCloser, Action::HoldFarther)
+            * HOLD_DISTANCE_SPEED
+            * time.delta_seconds();
+    if change == 0. {
+        return;
+    }
+    if let Some(mut grabbable) = state
+        .grabbing
+        .and_then(|held| grabbables.get_mut(held).ok())
+    {
+        grabbable.distance = (grabbable.distance + change)
+            .clamp(grabbable.min_hold_distance, grabbable.max_hold_distance);
     }
-    return;
 }
 
 fn player_grab(
-    keys: Res<Input<KeyCode>>,
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
     mut state: ResMut<PlayerState>,
-    mut commands: Commands,
+    mut grab_events: EventWriter<GrabEvent>,
     camera: Query<&GlobalTransform, With<FPSCam>>,
-    mut grabbables: Query<
-        (
-            Entity,
-            &mut Transform,
-            &mut Velocity,
-            &mut ExternalForce,
-            &mut Grabbable,
-            &mut GravityScale,
-        ),
-        With<Grabbable>,
-    >,
-    rapier_context: Res<RapierContext>,
+    body: Query<Entity, With<LocalPlayer>>,
+    mut grabbables: Query<(
+        Entity,
+        &GlobalTransform,
+        &ReadMassProperties,
+        &mut ExternalForce,
+        &mut Grabbable,
+        &mut GravityScale,
+    )>,
+    focus: Res<Focus>,
 ) {
-    if !keys.just_pressed(KeyCode::E) {
+    if suspended.any() || !actions.just_pressed(Action::Grab) {
         return;
     }
+    let body = match body.get_single() {
+        Ok(body) => body,
+        Err(_) => return,
+    };
 
-    let mut mapthing: HashMap<Entity, bool> = HashMap::new();
-
-    for (ent, _, _, _, grabby, _) in grabbables.iter() {
-        mapthing.insert(ent, grabby.grabbed);
+    if let Some(ent) = state.grabbing {
+        if let Ok((_, _, _, mut force, mut grabby, mut grav)) = grabbables.get_mut(ent) {
+            release_grabbable(&mut grabby, &mut force, &mut grav);
+        }
+        state.grabbing = None;
+        grab_events.send(GrabEvent::Released(ent));
+        return;
+    }
+    if state.throw_cooldown > 0. {
+        return;
     }
 
-    if state.grabbing.is_none() {
-        let pred = &|v| {
-            if let Some(v) = mapthing.get(&v) {
-                return !v.to_owned();
-            }
-            false
-        };
-        let filter = QueryFilter::new().predicate(pred);
-
-        for _global_transform in camera.iter() {
-            let transform = _global_transform.compute_transform();
-            let ray_pos = transform.translation;
-            let ray_dir = transform.forward();
-            let max_toi = 3.;
-            let solid = false;
-
-            if let Some((entity, toi)) =
-                rapier_context.cast_ray(ray_pos, ray_dir, max_toi, solid, filter)
-            {
-                if let Ok((ent, transform, velocity, mut force, mut grabb, mut grav)) =
-                    grabbables.get_mut(entity)
-                {
-                    if (grabb.grabbed) {
-                        return;
-                    }
-                    state.grabbing = Some(ent);
-                    grabb.grabbed = true;
-                    grav.0 = 0.;
-                }
+    // interactables share the focus, so it could be something that can't be picked up
+    let target = match focus.entity {
+        Some(target) => target,
+        None => return,
+    };
+    let transform = match camera.get_single() {
+        Ok(camera) => camera.compute_transform(),
+        Err(_) => return,
+    };
+    if let Ok((ent, object, mass, _, mut grabb, mut grav)) = grabbables.get_mut(target) {
+        if !grabb.is_free(mass) {
+            return;
+        }
+        state.grabbing = Some(ent);
+        hold_grabbable(&mut grabb, &mut grav, body);
+        // hold it by where the ray hit it
+        let hit = transform.translation + transform.forward() * focus.distance;
+        grabb.grab_point = object.affine().inverse().transform_point3(hit);
+        grab_events.send(GrabEvent::Grabbed(ent));
+    }
+}
 
-                //let hit_point = ray_pos + ray_dir * toi;
-            }
+/// Charges a throw while the throw action is held down with something in hand, and throws it
+/// along the camera's forward direction once it's let go
+fn player_throw(
+    time: Res<Time>,
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
+    mut state: ResMut<PlayerState>,
+    mut grab_events: EventWriter<GrabEvent>,
+    camera: Query<&GlobalTransform, With<FPSCam>>,
+    body: Query<&CharacterController, With<LocalPlayer>>,
+    mut grabbables: Query<(
+        &mut ExternalForce,
+        &mut ExternalImpulse,
+        &mut Grabbable,
+        &mut GravityScale,
+        &ReadMassProperties,
+    )>,
+) {
+    state.throw_cooldown = (state.throw_cooldown - time.delta_seconds()).max(0.);
+    let held = match state.grabbing {
+        // opening a menu cancels the throw
+        Some(held) if !suspended.any() => held,
+        _ => {
+            state.throw_charge = None;
+            return;
         }
-    } else {
-        if let Ok((ent, _, _, mut force, mut grabby, mut grav)) =
-            grabbables.get_mut(state.grabbing.unwrap())
-        {
-            grabby.grabbed = false;
-            state.grabbing = Option::None;
-
-            force.force = Vec3::ZERO;
-            grav.0 = GravityScale::default().0;
+    };
+
+    if actions.pressed(Action::Throw) {
+        // a press that began in a menu, or during the cooldown, doesn't start charging
+        if let Some(charge) = &mut state.throw_charge {
+            *charge += time.delta_seconds();
+        } else if actions.just_pressed(Action::Throw) && state.throw_cooldown == 0. {
+            state.throw_charge = Some(time.delta_seconds());
         }
+        return;
     }
+    let charge = match state.throw_charge.take() {
+        Some(charge) => charge,
+        None => return,
+    };
+
+    let (camera, controller) = match (camera.get_single(), body.get_single()) {
+        (Ok(camera), Ok(controller)) => (camera, controller),
+        _ => return,
+    };
+    if let Ok((mut force, mut impulse, mut grabbable, mut gravity, mass)) = grabbables.get_mut(held)
+    {
+        let direction = camera.compute_transform().forward();
+        let thrown = throw_impulse(direction, charge, controller.velocity, mass.0.mass);
+        release_grabbable(&mut grabbable, &mut force, &mut gravity);
+        impulse.impulse += thrown;
+        grab_events.send(GrabEvent::Thrown(held, thrown));
+    }
+    state.grabbing = None;
+    state.throw_cooldown = THROW_COOLDOWN_SECS;
 }
 
-/// Handles keyboard input and movement
-fn player_move(
-    keys: Res<Input<KeyCode>>,
-    time: Res<Time>,
+/// Samples the movement actions and look angles into the local player's `ControlInput`
+fn local_player_input(
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
     windows: Res<Windows>,
-    settings: Res<MovementSettings>,
-    state: Res<PlayerState>,
-    cam_query: Query<&GlobalTransform, With<FPSCam>>,
-    mut query: Query<(&mut ExternalForce, &Velocity), With<FPSBody>>,
+    look: Res<InputState>,
+    mut query: Query<&mut ControlInput, With<LocalPlayer>>,
 ) {
     let window = windows.get_primary().unwrap();
-    for global_trans in cam_query.iter() {
-        for (mut ext_impulse, body_velocity) in query.iter_mut() {
-            let mut velocity = Vec3::ZERO;
-            let mut y_velocity = 0.;
-            let local_z = global_trans.back();
-            let forward = -Vec3::new(local_z.x, 0., local_z.z);
-            let right = Vec3::new(local_z.z, 0., -local_z.x);
-
-            for key in keys.get_pressed() {
-                if window.cursor_grab_mode() == CursorGrabMode::Locked {
-                    match key {
-                        KeyCode::W => velocity += forward,
-                        KeyCode::S => velocity -= forward,
-                        KeyCode::A => velocity -= right,
-                        KeyCode::D => velocity += right,
-                        _ => (),
-                    }
-                }
-            }
-            if (keys.just_pressed(KeyCode::Space)) {
-                if state.grounded {
-                    y_velocity += tweak!(1000.0)
-                }
-            }
-
-            velocity = velocity.normalize();
-            if (velocity == Vec3::ZERO || velocity.is_nan()) && y_velocity == 0. {
-                ext_impulse.force = Vec3::ZERO;
+    for mut input in query.iter_mut() {
+        let mut movement = Vec2::ZERO;
+        if window.cursor_grab_mode() == CursorGrabMode::Locked && !suspended.any() {
+            movement = Vec2::new(
+                actions.axis(Action::MoveLeft, Action::MoveRight),
+                actions.axis(Action::MoveBack, Action::MoveForward),
+            )
+            .clamp_length_max(1.);
+        }
 
-                return;
-            }
-            velocity *= settings.max_speed;
-            velocity.max((settings.acceleration * time.delta().as_secs_f32()) * Vec3::ONE);
+        input.0 = PlayerCommand {
+            movement,
+            // keep an unconsumed jump until player_move has seen it
+            jump: input.0.jump || (!suspended.any() && actions.just_pressed(Action::Jump)),
+            yaw: look.yaw,
+            pitch: look.pitch,
+            ..Default::default()
+        };
+    }
+}
 
-            let needed_accel: Vec3 = (velocity - body_velocity.linvel) / time.delta().as_secs_f32();
+/// Moves every `FPSBody` according to its `ControlInput`, pushing the dynamic bodies with an
+/// `ExternalImpulse` it walks into. Bodies keep falling and settling on the ground outside of
+/// matches, they only ignore the input.
+fn player_move(
+    time: Res<Time>,
+    phase: Res<State<MatchPhase>>,
+    settings: Res<MovementSettings>,
+    rapier_config: Res<RapierConfiguration>,
+    rapier_context: Res<RapierContext>,
+    mut query: Query<
+        (
+            Entity,
+            &mut Transform,
+            &Collider,
+            &mut CharacterController,
+            &mut ControlInput,
+            &mut Grounded,
+        ),
+        With<FPSBody>,
+    >,
+    mut pushed: Query<(&RigidBody, &mut ExternalImpulse), Without<FPSBody>>,
+) {
+    let dt = time.delta_seconds();
+    // the bodies stand still along with everything else while the simulation is stopped
+    if dt == 0. || !rapier_config.physics_pipeline_active {
+        return;
+    }
+    let playing = *phase.current() == MatchPhase::Playing;
 
-            let newforce =
-                needed_accel.clamp(needed_accel, settings.max_acceleration_force * Vec3::ONE);
+    for (entity, mut transform, collider, mut controller, mut input, mut grounded) in
+        query.iter_mut()
+    {
+        let rotation = Quat::from_axis_angle(Vec3::Y, input.0.yaw);
+        let forward = rotation * Vec3::NEG_Z;
+        let right = rotation * Vec3::X;
+        let walking = if playing {
+            input.0.movement
+        } else {
+            Vec2::ZERO
+        };
+        // sticks can walk slower than full speed, nothing can go faster
+        let wish =
+            (forward * walking.y + right * walking.x).clamp_length_max(1.) * settings.max_speed;
 
-            let mut composed_velocity = Vec3::ZERO;
+        let mut velocity = controller.velocity;
+        let acceleration = if grounded.0 {
+            settings.acceleration
+        } else {
+            settings.acceleration * AIR_CONTROL
+        };
+        let horizontal = Vec3::new(velocity.x, 0., velocity.z);
+        let change = wish - horizontal;
+        let max_change = acceleration * dt;
+        let horizontal = if change.length() <= max_change {
+            wish
+        } else {
+            horizontal + change.normalize() * max_change
+        };
+        velocity.x = horizontal.x;
+        velocity.z = horizontal.z;
 
-            if !velocity.is_nan() {
-                composed_velocity = Vec3::new(newforce.x, 0., newforce.z);
+        if input.0.jump {
+            input.0.jump = false;
+            if grounded.0 && playing {
+                velocity.y = settings.jump_speed;
             }
+        }
+        velocity += rapier_config.gravity * dt;
 
-            if y_velocity != 0. {
-                composed_velocity.y = y_velocity;
-            }
+        let movement = move_character(
+            &rapier_context,
+            entity,
+            collider,
+            transform.translation,
+            velocity * dt,
+            grounded.0 && velocity.y <= 0.,
+            &settings,
+        );
+        transform.translation = movement.translation;
+        grounded.0 = movement.grounded;
 
-            if composed_velocity != Vec3::ZERO {
-                ext_impulse.force = composed_velocity;
+        controller.touching.clear();
+        for hit in movement.hits.iter() {
+            controller.touching.push(hit.entity);
+            // slopes too steep to walk up stop a body like walls do, rather than lifting it
+            let normal = if hit.walkable || hit.normal.y <= 0. {
+                hit.normal
+            } else {
+                Vec3::new(hit.normal.x, 0., hit.normal.z).normalize_or_zero()
+            };
+            let into = velocity.dot(-normal);
+            if into <= 0. {
+                continue;
             }
+            if let Ok((RigidBody::Dynamic, mut impulse)) = pushed.get_mut(hit.entity) {
+                let push = Vec3::new(-normal.x, 0., -normal.z).normalize_or_zero();
+                let strength = (into / settings.max_speed).min(1.);
+                impulse.impulse += push * settings.push_strength * strength * dt;
+            }
+            velocity += normal * into;
+        }
+        if grounded.0 {
+            velocity.y = velocity.y.max(0.);
         }
+        controller.velocity = velocity;
     }
 }
 
-/// Handles looking around if cursor is locked
+/// Looks around with the mouse while the cursor is locked, and with whatever else the look
+/// actions are bound to
 fn player_look(
+    time: Res<Time>,
     settings: Res<MovementSettings>,
+    bindings: Res<InputBindings>,
+    actions: Res<ActionState>,
+    suspended: Res<InputSuspended>,
     windows: Res<Windows>,
+    player: Res<PlayerState>,
     mut state: ResMut<InputState>,
-    mut mouse_move: EventReader<MouseMotion>,
     mut set: ParamSet<(
         Query<&mut Transform, With<FPSCam>>,
-        Query<&mut Transform, With<FPSBody>>,
+        Query<&mut Transform, (With<FPSBody>, With<LocalPlayer>)>,
     )>,
 ) {
+    if suspended.any() || player.rotating {
+        return;
+    }
     let window = windows.get_primary().unwrap();
 
-    for ev in mouse_move.iter() {
-        if window.cursor_grab_mode() == CursorGrabMode::Locked {
-            // Using smallest of height or width ensures equal vertical and horizontal sensitivity
-            let window_scale = window.height().min(window.width());
-            state.pitch -= (settings.sensitivity * ev.delta.y * window_scale).to_radians();
-            state.yaw -= (settings.sensitivity * ev.delta.x * window_scale).to_radians();
-        }
+    // in degrees, yaw to the left and pitch up
+    let mut delta = Vec2::new(
+        actions.axis(Action::LookRight, Action::LookLeft),
+        actions.axis(Action::LookDown, Action::LookUp),
+    ) * bindings.stick_look_speed
+        * time.delta_seconds();
+    if window.cursor_grab_mode() == CursorGrabMode::Locked {
+        // Using smallest of height or width ensures equal vertical and horizontal sensitivity
+        let window_scale = window.height().min(window.width());
+        delta -= actions.mouse_motion * settings.sensitivity * window_scale;
+    }
+    if delta == Vec2::ZERO {
+        return;
+    }
 
-        state.pitch = state.pitch.clamp(-1.54, 1.54);
+    state.yaw += delta.x.to_radians();
+    state.pitch = (state.pitch + delta.y.to_radians()).clamp(-1.54, 1.54);
 
-        // Order is important to prevent unintended roll
-        let new_transform =
-            Quat::from_axis_angle(Vec3::Y, 0.) * Quat::from_axis_angle(Vec3::X, state.pitch);
+    // Order is important to prevent unintended roll
+    let new_transform =
+        Quat::from_axis_angle(Vec3::Y, 0.) * Quat::from_axis_angle(Vec3::X, state.pitch);
 
-        let new_body_transform = Quat::from_axis_angle(Vec3::Y, state.yaw);
+    let new_body_transform = Quat::from_axis_angle(Vec3::Y, state.yaw);
 
-        for mut transform in set.p0().iter_mut() {
-            if (transform.rotation != new_transform) {
-                transform.rotation = new_transform;
-            }
+    for mut transform in set.p0().iter_mut() {
+        if (transform.rotation != new_transform) {
+            transform.rotation = new_transform;
         }
-        for mut transform in set.p1().iter_mut() {
-            if (transform.rotation != new_body_transform) {
-                transform.rotation = new_body_transform;
-            }
+    }
+    for mut transform in set.p1().iter_mut() {
+        if (transform.rotation != new_body_transform) {
+            transform.rotation = new_body_transform;
         }
     }
+}
 
-    for mut bodytransform in set.p0().iter_mut() {}
+/// Applies changes to the field of view to the `FPSCam`
+fn apply_fov(settings: Res<MovementSettings>, mut cameras: Query<&mut Projection, With<FPSCam>>) {
+    if !settings.is_changed() {
+        return;
+    }
+    for mut projection in cameras.iter_mut() {
+        if let Projection::Perspective(perspective) = &mut *projection {
+            perspective.fov = (settings.fov / 360.0) * (std::f32::consts::PI * 2.0);
+        }
+    }
 }
 
-fn cursor_grab(
-    keys: Res<Input<KeyCode>>,
-    mouse_click: Res<Input<MouseButton>>,
-    mut windows: ResMut<Windows>,
+/// Stops every body and drops everything held when a match ends, as nothing moves them until
+/// the next one starts
+fn stop_players(
+    mut state: ResMut<PlayerState>,
+    mut bodies: Query<(&mut CharacterController, &mut ControlInput), With<FPSBody>>,
+    mut grabbables: Query<
+        (&mut ExternalForce, &mut Grabbable, &mut GravityScale),
+        Without<FPSBody>,
+    >,
 ) {
-    let window = windows.get_primary_mut().unwrap();
-
-    //window.set_cursor_lock_mode(!window.cursor_locked());
-    //window.set_cursor_visibility(!window.cursor_visible());
-
-    if window.cursor_grab_mode() == CursorGrabMode::Locked && keys.just_pressed(KeyCode::Escape) {
-        window.set_cursor_grab_mode(CursorGrabMode::None);
-        // window.set_cursor_visibility(true);
-    } else if mouse_click.any_just_pressed([MouseButton::Left, MouseButton::Right]) {
-        window.set_cursor_grab_mode(CursorGrabMode::Locked);
-        // window.set_cursor_visibility(false);
+    for (mut controller, mut input) in bodies.iter_mut() {
+        controller.velocity = Vec3::ZERO;
+        input.0 = PlayerCommand::default();
+    }
+    for (mut force, mut grabbable, mut gravity) in grabbables.iter_mut() {
+        if grabbable.holder.is_some() {
+            release_grabbable(&mut grabbable, &mut force, &mut gravity);
+        }
     }
+    state.grabbing = None;
+    state.throw_charge = None;
+    state.rotating = false;
 }
 
-/// Contains everything needed to add first-person fly camera behavior to your game
+/// Contains everything needed to add first-person fly camera behavior to your game. Movement
+/// input and grabbing only run while the `MatchPhase` state is Playing, bodies fall and settle
+/// on the ground in every phase.
 pub struct PlayerPlugin;
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
@@ -426,15 +1025,38 @@ impl Plugin for PlayerPlugin {
             .init_resource::<InputState>()
             .init_resource::<MovementSettings>()
             .init_resource::<PlayerState>()
+            .init_resource::<InputSuspended>()
+            .add_event::<GrabEvent>()
             .add_startup_system(setup_player)
-            .add_startup_system(initial_grab_cursor)
             .add_system_set(SystemSet::new().with_run_criteria(FixedTimestep::step(0.017)))
-            .add_system(detect_ground)
-            .add_system(player_move)
+            .add_system_set(
+                SystemSet::on_update(MatchPhase::Playing)
+                    .with_system(local_player_input.label(PlayerSystem::Input))
+                    .with_system(rotate_held.before(player_look))
+                    .with_system(grabbing.after(PlayerSystem::Input))
+                    .with_system(adjust_hold_distance.before(grabbing))
+                    .with_system(
+                        break_grab_joints
+                            .label(PlayerSystem::Grab)
+                            .after(player_throw)
+                            .before(grabbing),
+                    )
+                    .with_system(player_grab.label(PlayerSystem::Grab).before(grabbing))
+                    .with_system(
+                        player_throw
+                            .label(PlayerSystem::Grab)
+                            .after(player_grab)
+                            .before(grabbing),
+                    ),
+            )
+            .add_system_set(SystemSet::on_exit(MatchPhase::Playing).with_system(stop_players))
+            .add_system(
+                player_move
+                    .label(PlayerSystem::Move)
+                    .after(PlayerSystem::Input),
+            )
+            .add_system(sync_grab_joints.after(PlayerSystem::Grab))
             .add_system(player_look)
-            .add_system(cursor_grab)
-            .add_system(rotate_with_mouse)
-            .add_system(grabbing)
-            .add_system(player_grab.before(grabbing));
+            .add_system(apply_fov);
     }
 }
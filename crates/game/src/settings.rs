@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::player::MovementSettings;
+
+/// Name of the game's directory in the platform's config directory.
+const CONFIG_DIR: &str = "short-game";
+/// The file `Settings` are kept in.
+const SETTINGS_FILE: &str = "settings.ron";
+
+/// Where the config file `name` is kept: in the game's directory in the platform's config
+/// directory, or the working directory on platforms without one.
+pub fn config_path(name: &str) -> PathBuf {
+    match dirs::config_dir() {
+        Some(dir) => dir.join(CONFIG_DIR).join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+/// Reads the config file at `path`, falling back to the defaults when it's missing or can't be
+/// read. A missing file is created with the defaults so there is something to edit.
+pub fn load_config<T: Serialize + DeserializeOwned + Default>(path: &Path) -> T {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            info!("using the defaults, {}: {}", path.display(), e);
+            let config = T::default();
+            save_config(path, &config);
+            return config;
+        }
+    };
+    match ron::from_str(&text) {
+        Ok(config) => config,
+        Err(e) => {
+            // leave the file alone, so whatever was wrong with it can be fixed by hand
+            warn!(
+                "could not read {}, using the defaults: {}",
+                path.display(),
+                e
+            );
+            T::default()
+        }
+    }
+}
+
+/// Writes `config` to the config file at `path`.
+pub fn save_config<T: Serialize>(path: &Path, config: &T) {
+    let text = match ron::ser::to_string_pretty(config, ron::ser::PrettyConfig::default()) {
+        Ok(text) => text,
+        Err(e) => {
+            warn!("could not serialize {}: {}", path.display(), e);
+            return;
+        }
+    };
+    if let Some(dir) = path.parent() {
+        // the working directory when there's no config directory, which exists already
+        let _ = fs::create_dir_all(dir);
+    }
+    if let Err(e) = fs::write(path, text) {
+        warn!("could not write {}: {}", path.display(), e);
+    }
+}
+
+/// The player's settings, as kept in `settings.ron`.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub movement: MovementSettings,
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        load_config(&config_path(SETTINGS_FILE))
+    }
+
+    pub fn save(&self) {
+        save_config(&config_path(SETTINGS_FILE), self);
+    }
+}
+
+/// Loads the player's settings into their resources
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut App) {
+        let settings = Settings::load();
+        app.insert_resource(settings.movement);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct TestConfig {
+        value: u32,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> Self {
+            Self { value: 7 }
+        }
+    }
+
+    /// A config file path of its own for each test, in a directory that doesn't exist yet.
+    fn test_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("short-game-test-{}", test));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("config.ron")
+    }
+
+    #[test]
+    fn test_missing_config_is_created() {
+        let path = test_path("missing");
+        assert_eq!(load_config::<TestConfig>(&path), TestConfig::default());
+        // written out, so there is something to edit
+        assert_eq!(
+            ron::from_str::<TestConfig>(&fs::read_to_string(&path).unwrap()).unwrap(),
+            TestConfig::default()
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_config_is_left_alone() {
+        let path = test_path("corrupt");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let corrupt = "(value: oops";
+        fs::write(&path, corrupt).unwrap();
+
+        assert_eq!(load_config::<TestConfig>(&path), TestConfig::default());
+        assert_eq!(fs::read_to_string(&path).unwrap(), corrupt);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_saved_config_is_loaded() {
+        let path = test_path("saved");
+        save_config(&path, &TestConfig { value: 3 });
+        assert_eq!(load_config::<TestConfig>(&path), TestConfig { value: 3 });
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
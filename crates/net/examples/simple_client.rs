@@ -14,7 +14,7 @@ fn main() {
         .expect("could not set socket to be nonblocking");
 
     App::new()
-        .insert_resource(Socket(socket))
+        .insert_resource(Socket::new(socket))
         .add_plugins(MinimalPlugins)
         .add_plugin(LogPlugin::default())
         .add_plugin(ClientPlugin)
@@ -1,32 +1,59 @@
-use std::{net::UdpSocket, time::Duration};
+use std::{env, net::UdpSocket, time::Duration};
 
 use bevy::{app::ScheduleRunnerSettings, log::LogPlugin, prelude::*};
-use net::{Message, NetworkEvent, ServerPlugin, Socket, Transport};
+use net::{
+    AdminPlugin, Message, NetworkEvent, ReloadLevel, ServerPlugin, Socket, ThreadedSocket,
+    Transport,
+};
 
 const LISTEN_ADDRESS: &str = "0.0.0.0:4567";
+/// Where the admin console listens when `ADMIN_TOKEN` is set. Connect with e.g.
+/// `nc 127.0.0.1 4568` and send `auth <token>` first.
+const ADMIN_ADDRESS: &str = "127.0.0.1:4568";
 
 fn main() {
     let socket = UdpSocket::bind(LISTEN_ADDRESS).expect("could not bind socket");
     socket
         .set_nonblocking(true)
         .expect("could not set socket to be nonblocking");
-    socket
-        .set_read_timeout(Some(Duration::from_secs(5)))
-        .expect("could not set read timeout");
+    // receive on a thread of its own, so packets are timestamped as they arrive rather than
+    // whenever the slow server loop gets to them
+    let socket = ThreadedSocket::spawn(socket).expect("could not spawn socket thread");
 
     info!("Server now listening on {}", LISTEN_ADDRESS);
 
-    App::new()
+    let mut app = App::new();
+    app
         // run the server at a reduced tick rate (100 ticks per minute)
         .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f32(
             60. / 100.,
         )))
-        .insert_resource(Socket(socket))
+        .insert_resource(Socket::new(socket))
         .add_plugins(MinimalPlugins)
         .add_plugin(LogPlugin::default())
         .add_plugin(ServerPlugin)
-        .add_system(connection_handler)
-        .run();
+        .add_system(connection_handler);
+
+    match env::var("ADMIN_TOKEN") {
+        Ok(token) => {
+            info!("Admin console listening on {}", ADMIN_ADDRESS);
+            app.add_plugin(AdminPlugin {
+                addr: ADMIN_ADDRESS.parse().unwrap(),
+                token,
+            })
+            .add_system(reload_handler);
+        }
+        Err(_) => info!("Set ADMIN_TOKEN to enable the admin console"),
+    }
+
+    app.run();
+}
+
+fn reload_handler(mut reloads: EventReader<ReloadLevel>) {
+    for _ in reloads.iter() {
+        // this server has no level of its own, a game would rebuild its world here
+        info!("level reload requested");
+    }
 }
 
 fn connection_handler(mut events: EventReader<NetworkEvent>, mut transport: ResMut<Transport>) {
@@ -43,6 +70,12 @@ fn connection_handler(mut events: EventReader<NetworkEvent>, mut transport: ResM
             NetworkEvent::Message(handle, msg) => {
                 info!("{} sent a message: {:?}", handle, msg);
             }
+            NetworkEvent::Rejected(handle, reason) => {
+                info!("{}: rejected, {}", handle, reason);
+            }
+            NetworkEvent::Malformed(handle, err) => {
+                error!("{} sent a malformed payload: {:?}", handle, err);
+            }
             NetworkEvent::SendError(err, msg) => {
                 error!(
                     "NetworkEvent::SendError (payload [{:?}]): {:?}",
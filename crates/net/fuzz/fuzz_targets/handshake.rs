@@ -0,0 +1,38 @@
+#![no_main]
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+use net::{admit, Admission};
+
+// Splits the input into datagrams on `\n`. The first byte of each picks one of a few peers and
+// the rest is the payload, so sequences of hellos and messages from several clients get mixed.
+fuzz_target!(|data: &[u8]| {
+    let mut connections = HashMap::new();
+    for (i, datagram) in data.split(|&byte| byte == b'\n').enumerate() {
+        let (peer, payload) = match datagram.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4000 + (*peer % 4) as u16));
+        let message = match net::decode(payload) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+
+        let was_connected = connections.contains_key(&addr);
+        match admit(
+            &mut connections,
+            addr,
+            Duration::from_millis(i as u64),
+            message,
+        ) {
+            Admission::Connected => assert!(!was_connected),
+            Admission::Message(_) => assert!(was_connected),
+            Admission::Rejected(_) => assert!(!connections.contains_key(&addr)),
+            Admission::Ignored => {}
+        }
+    }
+});
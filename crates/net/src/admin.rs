@@ -0,0 +1,496 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+use crate::tick::NetworkTickSettings;
+use crate::transport::Transport;
+use crate::NetworkResource;
+
+/// Longest line an admin may send, a longer one closes the connection.
+const MAX_LINE_LEN: usize = 1024;
+
+const HELP: &str = "\
+connections               list connections and traffic
+kick <address> [reason]   drop a connection
+ban <ip>                  drop every connection from an ip and ignore it from now on
+unban <ip>                lift a ban
+bans                      list banned ips
+timeout <seconds>         set how long a silent connection is kept
+tickrate <hz>             set how many network ticks run per second
+entities                  count the entities in the world
+reload                    reload the level";
+
+/// Sent when an admin asks for the level to be reloaded, for the app to act on.
+pub struct ReloadLevel;
+
+/// Adds a console to operate a running server without rebuilding it. Admins connect over TCP,
+/// authenticate with `auth <token>` and then send one command per line, `help` lists them.
+/// Every command is answered with its output followed by `ok`, or with `error: <reason>`.
+///
+/// The token goes over the wire in plain text, so bind the console to a loopback address.
+pub struct AdminPlugin {
+    pub addr: SocketAddr,
+    pub token: String,
+}
+
+impl Plugin for AdminPlugin {
+    fn build(&self, app: &mut App) {
+        assert!(!self.token.is_empty(), "the admin console needs a token");
+        let listener = TcpListener::bind(self.addr).expect("could not bind admin console");
+        listener
+            .set_nonblocking(true)
+            .expect("could not set admin console to be nonblocking");
+
+        app.insert_resource(AdminConsole {
+            listener,
+            token: self.token.clone(),
+            sessions: Vec::new(),
+        })
+        .add_event::<ReloadLevel>()
+        .add_system(admin_console_system);
+    }
+}
+
+#[derive(Resource)]
+struct AdminConsole {
+    listener: TcpListener,
+    token: String,
+    sessions: Vec<Session>,
+}
+
+/// A connection to the console and the bytes of partially read and written lines.
+struct Session {
+    stream: TcpStream,
+    addr: SocketAddr,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+    authenticated: bool,
+    /// Set after a failed authentication, the connection is closed once the reply is written.
+    closing: bool,
+    closed: bool,
+}
+
+fn admin_console_system(world: &mut World) {
+    world.resource_scope(|world, mut console: Mut<AdminConsole>| console.update(world));
+}
+
+impl AdminConsole {
+    /// Takes new connections and runs the commands that arrived since the last frame.
+    fn update(&mut self, world: &mut World) {
+        while let Ok((stream, addr)) = self.listener.accept() {
+            match Session::new(stream, addr) {
+                Ok(session) => {
+                    info!("admin connected from {}", addr);
+                    self.sessions.push(session);
+                }
+                Err(e) => warn!("could not set up admin connection from {}: {}", addr, e),
+            }
+        }
+
+        for session in self.sessions.iter_mut() {
+            for line in session.read_lines() {
+                if session.closing {
+                    break;
+                }
+                let reply = if session.authenticated {
+                    info!("admin {}: {}", session.addr, line);
+                    execute(world, &line)
+                } else {
+                    session.authenticate(&self.token, &line)
+                };
+                session.reply(reply);
+            }
+            session.flush();
+            if session.closing {
+                session.closed = true;
+            }
+        }
+        self.sessions.retain(|session| !session.closed);
+    }
+}
+
+impl Session {
+    fn new(stream: TcpStream, addr: SocketAddr) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            addr,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            authenticated: false,
+            closing: false,
+            closed: false,
+        })
+    }
+
+    /// Reads whatever arrived without blocking and returns the complete lines.
+    fn read_lines(&mut self) -> Vec<String> {
+        let mut chunk = [0; 1024];
+        while !self.closed {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.closed = true,
+                Ok(read) => self.incoming.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => self.closed = true,
+            }
+        }
+
+        let mut lines = Vec::new();
+        while let Some(end) = self.incoming.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.incoming.drain(..=end).collect();
+            lines.push(String::from_utf8_lossy(&line).trim().to_string());
+        }
+        if self.incoming.len() > MAX_LINE_LEN {
+            self.closed = true;
+        }
+        lines
+    }
+
+    fn authenticate(&mut self, token: &str, line: &str) -> Result<String, String> {
+        match line.strip_prefix("auth ") {
+            Some(given) if tokens_match(given.trim(), token) => {
+                info!("admin {} authenticated", self.addr);
+                self.authenticated = true;
+                Ok(String::new())
+            }
+            _ => {
+                warn!("admin {} failed to authenticate", self.addr);
+                // no second guesses on the same connection
+                self.closing = true;
+                Err("authentication failed".to_string())
+            }
+        }
+    }
+
+    fn reply(&mut self, reply: Result<String, String>) {
+        let text = match reply {
+            Ok(output) if output.is_empty() => "ok\n".to_string(),
+            Ok(output) => format!("{}\nok\n", output),
+            Err(reason) => format!("error: {}\n", reason),
+        };
+        self.outgoing.extend_from_slice(text.as_bytes());
+    }
+
+    /// Writes as much of the replies as the stream takes without blocking.
+    fn flush(&mut self) {
+        while !self.outgoing.is_empty() && !self.closed {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => self.closed = true,
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => self.closed = true,
+            }
+        }
+    }
+}
+
+/// Compares tokens without bailing out at the first difference, so timing doesn't tell how much
+/// of a guess was right.
+fn tokens_match(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Runs a single console command against the world.
+fn execute(world: &mut World, line: &str) -> Result<String, String> {
+    let mut args = line.split_whitespace();
+    match args.next() {
+        None => Ok(String::new()),
+        Some("help") => Ok(HELP.to_string()),
+        Some("connections") => connections(world),
+        Some("kick") => {
+            let addr: SocketAddr = parse(args.next(), "usage: kick <address> [reason]")?;
+            let reason = args.collect::<Vec<_>>().join(" ");
+            let reason = if reason.is_empty() {
+                "kicked by an admin".to_string()
+            } else {
+                reason
+            };
+            with_network(world, |net, transport| {
+                if net.kick(transport, addr, reason) {
+                    Ok(format!("kicked {}", addr))
+                } else {
+                    Err(format!("{} is not connected", addr))
+                }
+            })?
+        }
+        Some("ban") => {
+            let ip: IpAddr = parse(args.next(), "usage: ban <ip>")?;
+            with_network(world, |net, transport| {
+                net.banned.insert(ip);
+                let addrs: Vec<_> = net
+                    .connections
+                    .keys()
+                    .filter(|addr| addr.ip() == ip)
+                    .copied()
+                    .collect();
+                for addr in addrs.iter() {
+                    net.kick(transport, *addr, "banned".to_string());
+                }
+                format!("banned {}, dropped {} connections", ip, addrs.len())
+            })
+        }
+        Some("unban") => {
+            let ip: IpAddr = parse(args.next(), "usage: unban <ip>")?;
+            with_network(world, |net, _| {
+                if net.banned.remove(&ip) {
+                    Ok(format!("unbanned {}", ip))
+                } else {
+                    Err(format!("{} is not banned", ip))
+                }
+            })?
+        }
+        Some("bans") => with_network(world, |net, _| {
+            let mut bans: Vec<_> = net.banned.iter().map(IpAddr::to_string).collect();
+            bans.sort();
+            bans.join("\n")
+        }),
+        Some("timeout") => {
+            let secs: f32 = parse(args.next(), "usage: timeout <seconds>")?;
+            if !(secs.is_finite() && secs > 0.) {
+                return Err("the timeout has to be positive".to_string());
+            }
+            with_network(world, |net, _| {
+                net.idle_timeout = Duration::from_secs_f32(secs);
+                format!("idle timeout is now {}s", secs)
+            })
+        }
+        Some("tickrate") => {
+            let rate: f64 = parse(args.next(), "usage: tickrate <hz>")?;
+            if !(rate.is_finite() && rate > 0.) {
+                return Err("the tick rate has to be positive".to_string());
+            }
+            let mut settings = world
+                .get_resource_mut::<NetworkTickSettings>()
+                .ok_or("no network tick is running")?;
+            settings.rate = rate;
+            Ok(format!("tick rate is now {}hz", rate))
+        }
+        Some("entities") => Ok(format!(
+            "{} entities in {} archetypes",
+            world.entities().len(),
+            world.archetypes().len()
+        )),
+        Some("reload") => {
+            world.send_event(ReloadLevel);
+            Ok("reloading the level".to_string())
+        }
+        Some(unknown) => Err(format!("unknown command {}, try help", unknown)),
+    }
+}
+
+fn parse<T: std::str::FromStr>(arg: Option<&str>, usage: &str) -> Result<T, String> {
+    arg.and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| usage.to_string())
+}
+
+/// Runs `f` with the server's network resources, if there is a server.
+fn with_network<T>(
+    world: &mut World,
+    f: impl FnOnce(&mut NetworkResource, &mut Transport) -> T,
+) -> Result<T, String> {
+    if !world.contains_resource::<NetworkResource>() || !world.contains_resource::<Transport>() {
+        return Err("no server is running".to_string());
+    }
+    Ok(
+        world.resource_scope(|world, mut transport: Mut<Transport>| {
+            f(&mut world.resource_mut::<NetworkResource>(), &mut transport)
+        }),
+    )
+}
+
+fn connections(world: &World) -> Result<String, String> {
+    let net = world
+        .get_resource::<NetworkResource>()
+        .ok_or("no server is running")?;
+    let transport = world.get_resource::<Transport>();
+    let now = world
+        .get_resource::<Time>()
+        .map_or(Duration::ZERO, |time| time.elapsed());
+
+    let mut lines = vec![format!(
+        "{} connected, {} malformed, {} rejected, {} oversized, {} bytes sent ({:.2} of serialized)",
+        net.connections.len(),
+        net.stats.malformed,
+        net.stats.rejected,
+        net.stats.oversized,
+        net.stats.sent_bytes,
+        net.stats.compression_ratio()
+    )];
+    let mut connections: Vec<_> = net.connections.iter().collect();
+    connections.sort();
+    for (addr, last_update) in connections {
+        let peer = net.peers.get(addr).copied().unwrap_or_default();
+        lines.push(format!(
+            "{} last heard {:.1}s ago, in {} packets/{} bytes, out {} packets/{} bytes, {} reliable pending",
+            addr,
+            now.saturating_sub(*last_update).as_secs_f32(),
+            peer.packets_received,
+            peer.bytes_received,
+            peer.packets_sent,
+            peer.bytes_sent,
+            transport.map_or(0, |transport| transport.pending_reliable(*addr))
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+
+    use super::*;
+    use crate::Message;
+
+    fn server_world() -> World {
+        let mut world = World::new();
+        world.insert_resource(NetworkResource::default());
+        world.insert_resource(Transport::new());
+        world.insert_resource(NetworkTickSettings::default());
+        world.init_resource::<Events<ReloadLevel>>();
+        world
+    }
+
+    /// Runs the console until it answers `reader` with a line, or the connection is closed.
+    fn read_line(
+        console: &mut AdminConsole,
+        world: &mut World,
+        reader: &mut BufReader<TcpStream>,
+    ) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut line = String::new();
+        loop {
+            console.update(world);
+            match reader.read_line(&mut line) {
+                Ok(_) => return line,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    assert!(Instant::now() < deadline, "timed out waiting for a reply");
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    fn connect(console: &AdminConsole, lines: &[u8]) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(console.listener.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        stream.write_all(lines).unwrap();
+        BufReader::new(stream)
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secreT", "secret"));
+        assert!(!tokens_match("secret!", "secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut world = server_world();
+        let client: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        world
+            .resource_mut::<NetworkResource>()
+            .connections
+            .insert(client, Duration::ZERO);
+
+        execute(&mut world, "timeout 12").unwrap();
+        assert_eq!(
+            world.resource::<NetworkResource>().idle_timeout,
+            Duration::from_secs(12)
+        );
+        execute(&mut world, "tickrate 60").unwrap();
+        assert_eq!(world.resource::<NetworkTickSettings>().rate, 60.);
+        assert!(execute(&mut world, "tickrate 0").is_err());
+        assert!(execute(&mut world, "kick nowhere").is_err());
+        assert!(execute(&mut world, "bogus").is_err());
+
+        execute(&mut world, "ban 10.0.0.2").unwrap();
+        let net = world.resource::<NetworkResource>();
+        assert!(net.connections.is_empty());
+        assert!(net.banned.contains(&client.ip()));
+        // the client is told why it was dropped
+        let sent = world
+            .resource_mut::<Transport>()
+            .drain_messages_to_send(|_| true);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].destination, Some(client));
+
+        execute(&mut world, "reload").unwrap();
+        assert_eq!(world.resource::<Events<ReloadLevel>>().len(), 1);
+    }
+
+    #[test]
+    fn test_connections_report_peer_stats() {
+        let mut world = server_world();
+        let client: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let mut net = world.resource_mut::<NetworkResource>();
+        net.connections.insert(client, Duration::ZERO);
+        net.count_received(client, 40);
+        net.count_received(client, 2);
+        net.count_sent(client, 7);
+        world
+            .resource_mut::<Transport>()
+            .send_reliable_to(client, Message::Heartbeat);
+
+        let report = execute(&mut world, "connections").unwrap();
+        assert!(
+            report.contains(
+                "10.0.0.2:5000 last heard 0.0s ago, in 2 packets/42 bytes, out 1 packets/7 bytes, 1 reliable pending"
+            ),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn test_session() {
+        let mut world = server_world();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut console = AdminConsole {
+            listener,
+            token: "secret".to_string(),
+            sessions: Vec::new(),
+        };
+
+        let mut admin = connect(&console, b"auth secret\ntimeout 3\n");
+        assert_eq!(read_line(&mut console, &mut world, &mut admin), "ok\n");
+        assert_eq!(
+            read_line(&mut console, &mut world, &mut admin),
+            "idle timeout is now 3s\n"
+        );
+        assert_eq!(read_line(&mut console, &mut world, &mut admin), "ok\n");
+
+        let mut intruder = connect(&console, b"auth guess\ntimeout 1\n");
+        assert_eq!(
+            read_line(&mut console, &mut world, &mut intruder),
+            "error: authentication failed\n"
+        );
+        // closed without running anything else
+        assert_eq!(read_line(&mut console, &mut world, &mut intruder), "");
+        assert_eq!(
+            world.resource::<NetworkResource>().idle_timeout,
+            Duration::from_secs(3)
+        );
+    }
+}
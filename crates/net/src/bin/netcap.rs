@@ -0,0 +1,47 @@
+//! Prints every datagram of a capture written by `net::RecordingSocket`.
+//!
+//! Usage: `netcap <capture file>`
+
+use std::{env, process};
+
+use net::{decode, read_capture, Direction};
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: netcap <capture file>");
+            process::exit(2);
+        }
+    };
+    let records = match read_capture(&path) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("could not read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    for record in records {
+        let direction = match record.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        let time = record.time.as_secs_f64();
+        match decode(&record.payload) {
+            Ok(message) => println!(
+                "{:>12.6} #{:<6} {} {} {:?}",
+                time, record.poll, direction, record.peer, message
+            ),
+            Err(e) => println!(
+                "{:>12.6} #{:<6} {} {} malformed ({} bytes): {}",
+                time,
+                record.poll,
+                direction,
+                record.peer,
+                record.payload.len(),
+                e
+            ),
+        }
+    }
+}
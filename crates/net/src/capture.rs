@@ -0,0 +1,337 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+
+use crate::socket::DatagramSocket;
+
+/// Written at the start of every capture file.
+const MAGIC: &[u8; 8] = b"NETCAP2\n";
+
+/// Whether a captured datagram was sent or received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent = 0,
+    Received = 1,
+}
+
+/// A single datagram in a capture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Time since the capture was started.
+    pub time: Duration,
+    /// How many times the socket had been read until it ran dry, reporting `WouldBlock`, before
+    /// the datagram went through it. A replay hands datagrams out in the same batches.
+    pub poll: u64,
+    pub direction: Direction,
+    /// Who the datagram was sent to or received from.
+    pub peer: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+impl CaptureRecord {
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let peer = self.peer.to_string();
+        writer.write_all(&(self.time.as_micros() as u64).to_le_bytes())?;
+        writer.write_all(&self.poll.to_le_bytes())?;
+        writer.write_all(&[self.direction as u8, peer.len() as u8])?;
+        writer.write_all(peer.as_bytes())?;
+        writer.write_all(&(self.payload.len() as u32).to_le_bytes())?;
+        writer.write_all(&self.payload)
+    }
+
+    /// Reads the next record, `None` once the capture has ended.
+    fn read_from(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut time = [0; 8];
+        match reader.read_exact(&mut time) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut poll = [0; 8];
+        reader.read_exact(&mut poll)?;
+
+        let mut header = [0; 2];
+        reader.read_exact(&mut header)?;
+        let direction = match header[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => return Err(invalid_data("unknown direction")),
+        };
+
+        let mut peer = vec![0; header[1] as usize];
+        reader.read_exact(&mut peer)?;
+        let peer = String::from_utf8(peer)
+            .ok()
+            .and_then(|peer| peer.parse().ok())
+            .ok_or_else(|| invalid_data("invalid peer address"))?;
+
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let mut payload = vec![0; u32::from_le_bytes(len) as usize];
+        reader.read_exact(&mut payload)?;
+
+        Ok(Some(Self {
+            time: Duration::from_micros(u64::from_le_bytes(time)),
+            poll: u64::from_le_bytes(poll),
+            direction,
+            peer,
+            payload,
+        }))
+    }
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Reads every record of a capture file written by a `RecordingSocket`.
+pub fn read_capture(path: impl AsRef<Path>) -> io::Result<Vec<CaptureRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a capture file"));
+    }
+
+    let mut records = Vec::new();
+    while let Some(record) = CaptureRecord::read_from(&mut reader)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Wraps a socket, writing every datagram it sends or receives to a capture file. It should be
+/// the outermost socket, so it sees the reads of the network systems themselves.
+pub struct RecordingSocket<S> {
+    inner: S,
+    start: Instant,
+    /// Times the socket was read until it ran dry.
+    polls: AtomicU64,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl<S: DatagramSocket> RecordingSocket<S> {
+    /// Creates the capture file at `path`, replacing any existing one.
+    pub fn create(inner: S, path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        Ok(Self {
+            inner,
+            start: Instant::now(),
+            polls: AtomicU64::new(0),
+            file: Mutex::new(file),
+        })
+    }
+
+    fn record(&self, direction: Direction, peer: SocketAddr, payload: &[u8]) {
+        let record = CaptureRecord {
+            time: self.start.elapsed(),
+            poll: self.polls.load(Ordering::Relaxed),
+            direction,
+            peer,
+            payload: payload.to_vec(),
+        };
+        let mut file = self.file.lock().unwrap();
+        // flush right away, the app may exit without dropping its resources
+        if let Err(e) = record.write_to(&mut *file).and_then(|_| file.flush()) {
+            warn!("could not write to capture: {}", e);
+        }
+    }
+}
+
+impl<S: DatagramSocket> DatagramSocket for RecordingSocket<S> {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_with_arrival(buf)
+            .map(|(len, addr, _)| (len, addr))
+    }
+
+    fn recv_with_arrival(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<bevy::utils::Instant>)> {
+        match self.inner.recv_with_arrival(buf) {
+            Ok((len, addr, arrived)) => {
+                self.record(Direction::Received, addr, &buf[..len]);
+                Ok((len, addr, arrived))
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.polls.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let len = self.inner.send_to(buf, addr)?;
+        self.record(Direction::Sent, addr, &buf[..len]);
+        Ok(len)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.send(buf)?;
+        if let Ok(addr) = self.inner.peer_addr() {
+            self.record(Direction::Sent, addr, &buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+/// Plays back the datagrams received in a capture, in order and in the same batches per read of
+/// the network systems as they were recorded, so a session can be reproduced without its peers
+/// however long frames take. It should be the outermost socket, as the network systems' reads
+/// are what drive it. Everything sent through it is dropped.
+pub struct ReplaySocket {
+    received: Mutex<VecDeque<CaptureRecord>>,
+    /// Times the socket was read until it ran dry so far.
+    polls: AtomicU64,
+    peer: Option<SocketAddr>,
+}
+
+impl ReplaySocket {
+    pub fn new(records: Vec<CaptureRecord>) -> Self {
+        let received: VecDeque<_> = records
+            .into_iter()
+            .filter(|record| record.direction == Direction::Received)
+            .collect();
+        Self {
+            peer: received.front().map(|record| record.peer),
+            received: Mutex::new(received),
+            polls: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads the capture at `path` to replay.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        read_capture(path).map(Self::new)
+    }
+}
+
+impl DatagramSocket for ReplaySocket {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut received = self.received.lock().unwrap();
+        match received.front() {
+            Some(record) if record.poll <= self.polls.load(Ordering::Relaxed) => {
+                let record = received.pop_front().unwrap();
+                let len = record.payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&record.payload[..len]);
+                Ok((len, record.peer))
+            }
+            _ => {
+                // this read ran dry, the next one gets the next batch
+                self.polls.fetch_add(1, Ordering::Relaxed);
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer.ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_round_trip() {
+        let path = std::env::temp_dir().join("net_test_capture_round_trip.cap");
+        let records = vec![
+            CaptureRecord {
+                time: Duration::from_micros(10),
+                poll: 0,
+                direction: Direction::Sent,
+                peer: "127.0.0.1:4567".parse().unwrap(),
+                payload: b"\"Heartbeat\"".to_vec(),
+            },
+            CaptureRecord {
+                time: Duration::from_micros(2500),
+                poll: 3,
+                direction: Direction::Received,
+                peer: "[::1]:4567".parse().unwrap(),
+                payload: vec![],
+            },
+        ];
+
+        let mut file = File::create(&path).unwrap();
+        file.write_all(MAGIC).unwrap();
+        for record in &records {
+            record.write_to(&mut file).unwrap();
+        }
+        drop(file);
+
+        assert_eq!(read_capture(&path).unwrap(), records);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_only_received() {
+        let peer: SocketAddr = "127.0.0.1:4567".parse().unwrap();
+        let record = |direction, payload: &[u8]| CaptureRecord {
+            time: Duration::ZERO,
+            poll: 0,
+            direction,
+            peer,
+            payload: payload.to_vec(),
+        };
+        let socket = ReplaySocket::new(vec![
+            record(Direction::Sent, b"a"),
+            record(Direction::Received, b"b"),
+        ]);
+
+        let mut buf = [0; 8];
+        assert_eq!(socket.recv_from(&mut buf).unwrap(), (1, peer));
+        assert_eq!(&buf[..1], b"b");
+        assert_eq!(
+            socket.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_replay_in_recorded_batches() {
+        let peer: SocketAddr = "127.0.0.1:4567".parse().unwrap();
+        let record = |poll, payload: &[u8]| CaptureRecord {
+            // recorded times play no part, however long it takes to read them back
+            time: Duration::from_secs(3600),
+            poll,
+            direction: Direction::Received,
+            peer,
+            payload: payload.to_vec(),
+        };
+        let socket = ReplaySocket::new(vec![record(0, b"a"), record(0, b"b"), record(2, b"c")]);
+
+        let mut buf = [0; 8];
+        let mut drain = || {
+            let mut batch = Vec::new();
+            while let Ok((len, _)) = socket.recv_from(&mut buf) {
+                batch.push(buf[..len].to_vec());
+            }
+            batch
+        };
+        assert_eq!(drain(), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert!(drain().is_empty());
+        assert_eq!(drain(), vec![b"c".to_vec()]);
+        assert!(drain().is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+
+use bevy::prelude::Resource;
+use lz4_flex::block;
+
+use crate::Message;
+
+/// Largest payload that is decoded, in bytes. Receive buffers hold one byte more, so oversized
+/// datagrams show up as too large instead of being silently truncated.
+pub const MAX_PAYLOAD_LEN: usize = 512;
+/// Largest message a compressed payload may expand to, in bytes.
+const MAX_MESSAGE_LEN: usize = 16 * 1024;
+/// Deepest nesting of arrays and objects that is decoded. No message needs more than a few
+/// levels, this keeps hostile payloads from recursing deep into the deserializer.
+const MAX_DEPTH: usize = 8;
+/// Serialized messages shorter than this aren't worth compressing.
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 128;
+
+/// Set in a payload's header byte when its body is LZ4 compressed, prefixed with its
+/// uncompressed length.
+const FLAG_LZ4: u8 = 1 << 0;
+/// Set along with `FLAG_LZ4` when the body was compressed against `DICTIONARY`.
+const FLAG_DICTIONARY: u8 = 1 << 1;
+const LZ4_WITH_DICTIONARY: u8 = FLAG_LZ4 | FLAG_DICTIONARY;
+
+/// Shared by every peer to compress small messages, which on their own repeat too little to
+/// shrink. Seeded with the shape of the messages replicated every tick. Any change to it needs
+/// a bump of `PROTOCOL_VERSION`.
+const DICTIONARY: &[u8] = br#"{"PlayerState":{"id":0,"translation":[0.0,0.0,0.0],"velocity":[0.0,0.0,0.0],"yaw":0.0}}{"ObjectState":{"object":0,"translation":[0.0,0.0,0.0],"rotation":[0.0,0.0,0.0,1.0],"linvel":[0.0,0.0,0.0],"angvel":[0.0,0.0,0.0]}}{"Command":{"movement":[0.0,0.0],"jump":false,"yaw":0.0,"pitch":0.0,"view_tick":0,"interpolation_delay":0.0}}{"GrabState":{"object":0,"holder":null}}{"ObjectAuthority":{"object":0,"owner":null}}"#;
+
+/// How outgoing messages are compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+    /// LZ4 against the built-in dictionary of message shapes.
+    Lz4Dictionary,
+}
+
+/// How outgoing messages are framed into payloads. Incoming payloads are decoded whatever codec
+/// their sender used, so peers don't need to agree on one.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct Codec {
+    pub compression: Compression,
+    /// Serialized messages shorter than this many bytes are sent uncompressed.
+    pub threshold: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self {
+            compression: Compression::Lz4Dictionary,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+}
+
+impl Codec {
+    /// Frames a serialized message into a payload: a header byte of flags followed by the
+    /// message, compressed when that makes it smaller.
+    pub fn encode(&self, message: &[u8]) -> Vec<u8> {
+        let compressed = match self.compression {
+            _ if message.len() < self.threshold => None,
+            Compression::None => None,
+            Compression::Lz4 => Some((FLAG_LZ4, block::compress_prepend_size(message))),
+            Compression::Lz4Dictionary => Some((
+                LZ4_WITH_DICTIONARY,
+                block::compress_prepend_size_with_dict(message, DICTIONARY),
+            )),
+        };
+
+        let mut payload = Vec::with_capacity(message.len() + 1);
+        match compressed {
+            Some((flags, body)) if body.len() < message.len() => {
+                payload.push(flags);
+                payload.extend_from_slice(&body);
+            }
+            _ => {
+                payload.push(0);
+                payload.extend_from_slice(message);
+            }
+        }
+        payload
+    }
+}
+
+/// Why a payload couldn't be turned into a `Message`.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The payload, or the message it decompresses to, is longer than allowed.
+    TooLarge(usize),
+    /// The header byte is missing or has flags this build doesn't know.
+    BadHeader,
+    /// The body is flagged as compressed but doesn't decompress.
+    BadCompression,
+    /// Arrays or objects are nested deeper than any message needs.
+    TooDeep,
+    /// The payload isn't a valid message.
+    Invalid(serde_json::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooLarge(len) => write!(f, "{} bytes is too large", len),
+            DecodeError::BadHeader => write!(f, "missing or unknown header flags"),
+            DecodeError::BadCompression => write!(f, "body doesn't decompress"),
+            DecodeError::TooDeep => write!(f, "payload nested deeper than {}", MAX_DEPTH),
+            DecodeError::Invalid(e) => write!(f, "invalid message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Deserializes the payload of a datagram, as framed by `Codec::encode`, into a message. The
+/// payload is checked against the limits above before anything is allocated for it or it
+/// reaches the deserializer.
+pub fn decode(payload: &[u8]) -> Result<Message, DecodeError> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(DecodeError::TooLarge(payload.len()));
+    }
+    let (&flags, body) = payload.split_first().ok_or(DecodeError::BadHeader)?;
+    let dictionary: &[u8] = match flags {
+        0 => return deserialize(body),
+        FLAG_LZ4 => &[],
+        LZ4_WITH_DICTIONARY => DICTIONARY,
+        _ => return Err(DecodeError::BadHeader),
+    };
+
+    let (len, compressed) =
+        block::uncompressed_size(body).map_err(|_| DecodeError::BadCompression)?;
+    if len > MAX_MESSAGE_LEN {
+        return Err(DecodeError::TooLarge(len));
+    }
+    let message = block::decompress_with_dict(compressed, len, dictionary)
+        .map_err(|_| DecodeError::BadCompression)?;
+    deserialize(&message)
+}
+
+fn deserialize(message: &[u8]) -> Result<Message, DecodeError> {
+    if nesting_depth(message) > MAX_DEPTH {
+        return Err(DecodeError::TooDeep);
+    }
+    serde_json::from_slice(message).map_err(DecodeError::Invalid)
+}
+
+/// Deepest nesting of arrays and objects in a JSON document, ignoring brackets inside strings.
+fn nesting_depth(json: &[u8]) -> usize {
+    let mut depth = 0usize;
+    let mut deepest = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else {
+            match byte {
+                b'"' => in_string = true,
+                b'[' | b'{' => {
+                    depth += 1;
+                    deepest = deepest.max(depth);
+                }
+                b']' | b'}' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+    }
+    deepest
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Vec3;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn encode(codec: Codec, message: &Message) -> Vec<u8> {
+        codec.encode(&serde_json::to_vec(message).unwrap())
+    }
+
+    #[test]
+    fn test_decode_message() {
+        let payload = encode(Codec::default(), &Message::PlayerJoined(3, Vec3::ONE));
+        assert!(matches!(
+            decode(&payload),
+            Ok(Message::PlayerJoined(3, translation)) if translation == Vec3::ONE
+        ));
+    }
+
+    #[test]
+    fn test_compression() {
+        let message = Message::PlayerState {
+            id: 1,
+            translation: Vec3::new(1.5, 0.25, -3.),
+            velocity: Vec3::ZERO,
+            yaw: 0.5,
+        };
+        let serialized = serde_json::to_vec(&message).unwrap();
+        for compression in [
+            Compression::None,
+            Compression::Lz4,
+            Compression::Lz4Dictionary,
+        ] {
+            let codec = Codec {
+                compression,
+                threshold: 0,
+            };
+            let payload = codec.encode(&serialized);
+            assert!(payload.len() <= serialized.len() + 1);
+            assert!(matches!(
+                decode(&payload),
+                Ok(Message::PlayerState { id: 1, .. })
+            ));
+        }
+
+        let dictionary = Codec {
+            compression: Compression::Lz4Dictionary,
+            threshold: 0,
+        };
+        assert_eq!(dictionary.encode(&serialized)[0], LZ4_WITH_DICTIONARY);
+    }
+
+    #[test]
+    fn test_decode_rejects_decompression_bomb() {
+        let mut payload = vec![FLAG_LZ4];
+        payload.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(decode(&payload), Err(DecodeError::TooLarge(_))));
+    }
+
+    #[test]
+    fn test_nesting_depth_ignores_strings() {
+        assert_eq!(nesting_depth(br#"{"a":["[[[\"{{"]}"#), 2);
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_arbitrary_bytes(payload in prop::collection::vec(any::<u8>(), 0..2048)) {
+            let _ = decode(&payload);
+        }
+
+        #[test]
+        fn test_decode_rejects_oversized(extra in 1..4096usize) {
+            let payload = vec![b' '; MAX_PAYLOAD_LEN + extra];
+            prop_assert!(matches!(decode(&payload), Err(DecodeError::TooLarge(_))));
+        }
+
+        #[test]
+        fn test_decode_rejects_deep_nesting(depth in MAX_DEPTH + 1..MAX_MESSAGE_LEN / 2) {
+            let message = "[".repeat(depth) + &"]".repeat(depth);
+            let payload = Codec::default().encode(message.as_bytes());
+            prop_assert!(matches!(decode(&payload), Err(DecodeError::TooDeep)));
+        }
+
+        #[test]
+        fn test_decode_arbitrary_compressed(body in prop::collection::vec(any::<u8>(), 0..512)) {
+            for flags in [FLAG_LZ4, LZ4_WITH_DICTIONARY] {
+                let mut payload = vec![flags];
+                payload.extend_from_slice(&body);
+                let _ = decode(&payload);
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use std::{io, net::SocketAddr};
 
-use crate::{message::OutgoingMessage, Message};
+use crate::{message::OutgoingMessage, DecodeError, Message, RejectReason};
 
 pub enum NetworkEvent {
     // A message was received from a client
@@ -9,6 +9,11 @@ pub enum NetworkEvent {
     Connected(SocketAddr),
     // A client has disconnected from us
     Disconnected(SocketAddr),
+    // A peer's protocol doesn't match ours, sent by the server when it refuses a client and by
+    // the client when the server refuses it
+    Rejected(SocketAddr, RejectReason),
+    // A payload couldn't be deserialized into a message
+    Malformed(SocketAddr, DecodeError),
     // An error occurred while receiving a message
     RecvError(io::Error),
     // An error occurred while sending a message
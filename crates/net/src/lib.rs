@@ -1,16 +1,51 @@
+#[cfg(not(target_arch = "wasm32"))]
+mod admin;
+mod capture;
+mod codec;
 mod events;
 mod message;
+mod protocol;
+mod reliable;
+mod socket;
+#[cfg(not(target_arch = "wasm32"))]
+mod stream;
 mod systems;
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test_util;
+#[cfg(not(target_arch = "wasm32"))]
+mod threaded;
+mod tick;
 mod transport;
+#[cfg(not(target_arch = "wasm32"))]
+mod websocket;
+#[cfg(target_arch = "wasm32")]
+mod websocket_web;
 
-use std::collections::HashMap;
-use std::net::{SocketAddr, UdpSocket};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
 use std::time::Duration;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::admin::{AdminPlugin, ReloadLevel};
+pub use self::capture::{read_capture, CaptureRecord, Direction, RecordingSocket, ReplaySocket};
+pub use self::codec::{decode, Codec, Compression, DecodeError, MAX_PAYLOAD_LEN};
 pub use self::events::NetworkEvent;
-pub use self::message::Message;
+pub use self::message::{MatchPhase, Message, ObjectId, PlayerCommand, PlayerId};
+pub use self::protocol::{
+    admit, check_compatibility, protocol_hash, Admission, RejectReason, PROTOCOL_VERSION,
+};
+pub use self::socket::{DatagramSocket, SocketSet};
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::stream::{StreamClient, StreamListener};
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::threaded::ThreadedSocket;
+pub use self::tick::{NetworkTick, NetworkTickSettings};
 pub use self::transport::Transport;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::websocket::{WebSocketClient, WebSocketListener};
+#[cfg(target_arch = "wasm32")]
+pub use self::websocket_web::WebSocketClient;
 
 use bevy::prelude::*;
 
@@ -26,6 +61,82 @@ pub struct NetworkResource {
     // Hashmap of each live connection and their last known packet activity
     pub connections: HashMap<SocketAddr, Duration>,
     pub idle_timeout: Duration,
+    pub stats: NetworkStats,
+    /// Traffic of each live connection, forgotten along with the connection.
+    pub peers: HashMap<SocketAddr, PeerStats>,
+    /// Addresses the server ignores everything from.
+    pub banned: HashSet<IpAddr>,
+    reliable: reliable::ReliableReceiver,
+    /// Connections dropped by `kick` that the game hasn't been told about yet.
+    kicked: Vec<SocketAddr>,
+}
+
+impl NetworkResource {
+    /// Drops the connection to `addr`, telling the client why with a `Message::Kicked`. The game
+    /// hears of it as a `NetworkEvent::Disconnected` on the next network tick. Returns whether
+    /// `addr` was connected.
+    pub fn kick(&mut self, transport: &mut Transport, addr: SocketAddr, reason: String) -> bool {
+        if self.connections.remove(&addr).is_none() {
+            return false;
+        }
+        self.peers.remove(&addr);
+        transport.send_reliable_to(addr, Message::Kicked(reason));
+        self.kicked.push(addr);
+        true
+    }
+
+    /// Counts a payload of `len` bytes received from `addr`, if it is connected.
+    pub(crate) fn count_received(&mut self, addr: SocketAddr, len: usize) {
+        if self.connections.contains_key(&addr) {
+            let peer = self.peers.entry(addr).or_default();
+            peer.packets_received += 1;
+            peer.bytes_received += len as u64;
+        }
+    }
+
+    /// Counts a payload of `len` bytes sent to `addr`, if it is connected.
+    pub(crate) fn count_sent(&mut self, addr: SocketAddr, len: usize) {
+        if self.connections.contains_key(&addr) {
+            let peer = self.peers.entry(addr).or_default();
+            peer.packets_sent += 1;
+            peer.bytes_sent += len as u64;
+        }
+    }
+}
+
+/// Counters of the traffic through a peer's socket.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct NetworkStats {
+    /// Payloads that couldn't be deserialized into a `Message`.
+    pub malformed: u64,
+    /// Clients refused for speaking an incompatible protocol.
+    pub rejected: u64,
+    /// Messages not sent because they were over `MAX_PAYLOAD_LEN`.
+    pub oversized: u64,
+    /// Bytes of messages queued to send, before they were compressed.
+    pub serialized_bytes: u64,
+    /// Bytes of payloads sent, after compression and framing.
+    pub sent_bytes: u64,
+}
+
+/// Counters of the traffic exchanged with a single connected peer, in payloads as they went
+/// through the socket.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct PeerStats {
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+}
+
+impl NetworkStats {
+    /// Bytes sent per byte of serialized messages, below 1 when compression pays off.
+    pub fn compression_ratio(&self) -> f32 {
+        if self.serialized_bytes == 0 {
+            return 1.;
+        }
+        self.sent_bytes as f32 / self.serialized_bytes as f32
+    }
 }
 
 impl Default for NetworkResource {
@@ -33,21 +144,41 @@ impl Default for NetworkResource {
         Self {
             connections: Default::default(),
             idle_timeout: Duration::from_secs_f32(DEFAULT_IDLE_TIMEOUT_SECS),
+            stats: Default::default(),
+            peers: Default::default(),
+            banned: Default::default(),
+            reliable: Default::default(),
+            kicked: Default::default(),
         }
     }
 }
 
 #[derive(Resource)]
-pub struct Socket(pub UdpSocket);
+pub struct Socket(pub Box<dyn DatagramSocket>);
+
+impl Socket {
+    pub fn new(socket: impl DatagramSocket) -> Self {
+        Self(Box::new(socket))
+    }
+}
 
 impl Deref for Socket {
-    type Target = UdpSocket;
+    type Target = dyn DatagramSocket;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &*self.0
     }
 }
 
+/// Stages the network systems run in, once for every `NetworkTick` due. `Receive` runs before
+/// `CoreStage::PreUpdate`, so gameplay sees this tick's messages, and `Send` after
+/// `CoreStage::PostUpdate`, so everything gameplay queued goes out in the same tick.
+#[derive(Clone, Hash, Debug, PartialEq, Eq, StageLabel)]
+pub enum NetworkStage {
+    Receive,
+    Send,
+}
+
 /// Label for network related systems.
 #[derive(Clone, Hash, Debug, PartialEq, Eq, SystemLabel)]
 pub enum NetworkSystem {
@@ -67,16 +198,57 @@ pub enum ClientSystem {
     Heartbeat,
 }
 
+/// Adds the network stages and the resources that pace them.
+struct NetworkTickPlugin;
+
+impl Plugin for NetworkTickPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetworkTickSettings>()
+            .init_resource::<NetworkTick>()
+            .add_system_to_stage(
+                CoreStage::First,
+                tick::advance_network_tick_system.after(bevy::time::TimeSystem),
+            )
+            .add_stage_before(
+                CoreStage::PreUpdate,
+                NetworkStage::Receive,
+                SystemStage::parallel().with_run_criteria(tick::network_tick_criteria),
+            )
+            .add_stage_after(
+                CoreStage::PostUpdate,
+                NetworkStage::Send,
+                SystemStage::parallel().with_run_criteria(tick::network_tick_criteria),
+            );
+    }
+}
+
 pub struct ServerPlugin;
 
 impl Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(NetworkResource::default())
+            .init_resource::<Codec>()
             .insert_resource(transport::Transport::new())
             .add_event::<events::NetworkEvent>()
-            .add_system(systems::server_recv_packet_system.label(NetworkSystem::Receive))
-            .add_system(systems::send_packet_system.label(NetworkSystem::Send))
-            .add_system(systems::idle_timeout_system.label(ServerSystem::IdleTimeout));
+            .add_plugin(NetworkTickPlugin)
+            .add_system_to_stage(
+                NetworkStage::Receive,
+                systems::server_recv_packet_system.label(NetworkSystem::Receive),
+            )
+            .add_system_to_stage(
+                NetworkStage::Receive,
+                systems::idle_timeout_system
+                    .label(ServerSystem::IdleTimeout)
+                    .after(NetworkSystem::Receive),
+            )
+            .add_system_to_stage(
+                NetworkStage::Send,
+                systems::resend_reliable_system.before(NetworkSystem::Send),
+            )
+            .add_system_to_stage(
+                NetworkStage::Send,
+                systems::send_packet_system.label(NetworkSystem::Send),
+            );
     }
 }
 
@@ -87,14 +259,28 @@ pub struct ClientPlugin;
 
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(transport::Transport::new())
+        app.insert_resource(NetworkResource::default())
+            .init_resource::<Codec>()
+            .insert_resource(transport::Transport::new())
             .insert_resource(HeartbeatTimer(Timer::from_seconds(
                 DEFAULT_HEARTBEAT_TICK_RATE_SECS,
                 TimerMode::Repeating,
             )))
             .add_event::<events::NetworkEvent>()
-            .add_system(systems::client_recv_packet_system.label(NetworkSystem::Receive))
-            .add_system(systems::send_packet_system.label(NetworkSystem::Send))
+            .add_startup_system(systems::client_hello_system)
+            .add_plugin(NetworkTickPlugin)
+            .add_system_to_stage(
+                NetworkStage::Receive,
+                systems::client_recv_packet_system.label(NetworkSystem::Receive),
+            )
+            .add_system_to_stage(
+                NetworkStage::Send,
+                systems::resend_reliable_system.before(NetworkSystem::Send),
+            )
+            .add_system_to_stage(
+                NetworkStage::Send,
+                systems::send_packet_system.label(NetworkSystem::Send),
+            )
             .add_system(systems::auto_heartbeat_system.label(ClientSystem::Heartbeat));
     }
 }
@@ -1,11 +1,140 @@
-use bevy::prelude::Vec3;
+use bevy::prelude::{Quat, Vec2, Vec3};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
+use crate::protocol::RejectReason;
+
+/// Identifies a player across every peer taking part in a session.
+pub type PlayerId = u64;
+/// Identifies a networked object across every peer taking part in a session.
+pub type ObjectId = u64;
+
+/// Input sampled from a player for a single frame. Clients send these to the server, which
+/// applies them to the player's body.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlayerCommand {
+    /// Requested movement relative to the player's facing, `x` to the right and `y` forward.
+    pub movement: Vec2,
+    pub jump: bool,
+    pub yaw: f32,
+    pub pitch: f32,
+    /// The latest server tick the client had received when it sampled this input.
+    pub view_tick: u64,
+    /// How far behind `view_tick` the client shows other players and objects, in seconds.
+    pub interpolation_delay: f32,
+}
+
+/// Phases a match goes through, decided by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MatchPhase {
+    /// Waiting for enough players to be ready.
+    Lobby,
+    /// Everyone is ready, the match starts when the countdown runs out.
+    Countdown,
+    Playing,
+    /// The match is over, the lobby opens again shortly.
+    Results,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Heartbeat,
+    /// First message a client sends, announcing which protocol it speaks.
+    Hello {
+        version: u32,
+        hash: u64,
+    },
+    /// Sent by the server to a client whose protocol it can't talk to.
+    Rejected(RejectReason),
+    /// Sent by the server to a client it dropped, with the reason.
+    Kicked(String),
+    /// A message that is sent again until the peer acknowledges it, see
+    /// `Transport::send_reliable`.
+    Reliable {
+        seq: u32,
+        message: Box<Message>,
+    },
+    /// Acknowledges the `Reliable` message with the given sequence number.
+    Ack(u32),
     Positional(Vec3),
+    /// Number of the server tick the state sent after it belongs to.
+    Tick(u64),
+    /// Sent by the server to a newly connected client with the id of its own player.
+    Welcome(PlayerId),
+    /// A player's body was spawned at the given position.
+    PlayerJoined(PlayerId, Vec3),
+    /// A player's body was removed from the world.
+    PlayerLeft(PlayerId),
+    /// Input from a client for the player it controls.
+    Command(PlayerCommand),
+    /// Authoritative state of a player's body, replicated by the server.
+    PlayerState {
+        id: PlayerId,
+        translation: Vec3,
+        velocity: Vec3,
+        yaw: f32,
+    },
+    /// Asks the server to let the sending client pick up an object.
+    Grab(ObjectId),
+    /// Tells the server the sending client let go of an object.
+    Release(ObjectId),
+    /// Tells the server the sending client threw an object it held, with the impulse it gave it.
+    Throw {
+        object: ObjectId,
+        impulse: Vec3,
+    },
+    /// Asks the server to let the sending client interact with an object.
+    Interact(ObjectId),
+    /// A player interacted with an object, sent by the server to everyone once it agreed to it.
+    Interacted {
+        object: ObjectId,
+        player: PlayerId,
+    },
+    /// The server reloaded the level, every peer puts its objects back the way they were at
+    /// startup.
+    LevelReloaded,
+    /// An object was used up or taken by a player, sent by the server to clients that join after.
+    ObjectRemoved(ObjectId),
+    /// Whether an object that switches on and off, such as a door or lever, is on. Sent by the
+    /// server to clients that join after it was switched.
+    SwitchState {
+        object: ObjectId,
+        on: bool,
+    },
+    /// Who holds an object, sent by the server when it changes or a grab is denied.
+    GrabState {
+        object: ObjectId,
+        holder: Option<PlayerId>,
+    },
+    /// State of an object's rigid body, sent by whichever peer simulates it.
+    ObjectState {
+        object: ObjectId,
+        translation: Vec3,
+        rotation: Quat,
+        linvel: Vec3,
+        angvel: Vec3,
+    },
+    /// Which player simulates an object, `None` when the server does.
+    ObjectAuthority {
+        object: ObjectId,
+        owner: Option<PlayerId>,
+    },
+    /// Tells the server whether the sending client is ready for the match to start.
+    Ready(bool),
+    /// The phase of the server's match, sent whenever it or the set of ready players changes.
+    MatchState {
+        phase: MatchPhase,
+        /// Seconds left of the phase, `None` when it lasts until players are ready.
+        remaining: Option<f32>,
+        ready: Vec<PlayerId>,
+    },
+    /// A line of text chat. Clients send it with an empty `sender`, which the server fills in
+    /// along with the time it relayed the line, in seconds since it started.
+    Chat {
+        sender: String,
+        time: u64,
+        text: String,
+    },
 }
 
 pub struct OutgoingMessage {
@@ -0,0 +1,522 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::de::value::U32Deserializer;
+use serde::de::{
+    self, DeserializeSeed, EnumAccess, IntoDeserializer, SeqAccess, VariantAccess, Visitor,
+};
+use serde::{forward_to_deserialize_any, Deserialize, Deserializer, Serialize};
+
+use crate::Message;
+
+/// Version of the wire protocol. Bump this whenever the meaning of a message changes while its
+/// shape stays the same, which `protocol_hash` can't detect on its own.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Why a server refused a client's `Message::Hello`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The peers were built with different `PROTOCOL_VERSION`s.
+    VersionMismatch { server: u32, client: u32 },
+    /// The peers agree on the version but not on the shape of the messages.
+    HashMismatch { server: u64, client: u64 },
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::VersionMismatch { server, client } => write!(
+                f,
+                "protocol version mismatch (server {}, client {})",
+                server, client
+            ),
+            RejectReason::HashMismatch { server, client } => write!(
+                f,
+                "message schema mismatch (server {:016x}, client {:016x})",
+                server, client
+            ),
+        }
+    }
+}
+
+/// Checks whether a client announcing `version` and `hash` can talk to this build.
+pub fn check_compatibility(version: u32, hash: u64) -> Result<(), RejectReason> {
+    if version != PROTOCOL_VERSION {
+        return Err(RejectReason::VersionMismatch {
+            server: PROTOCOL_VERSION,
+            client: version,
+        });
+    }
+    let local = protocol_hash();
+    if hash != local {
+        return Err(RejectReason::HashMismatch {
+            server: local,
+            client: hash,
+        });
+    }
+    Ok(())
+}
+
+/// What the server makes of a message, given the clients it is connected to.
+#[derive(Debug)]
+pub enum Admission {
+    /// A message from a connected client, for the game to handle.
+    Message(Message),
+    /// A compatible hello, the client is now connected.
+    Connected,
+    /// A hello the server can't talk to.
+    Rejected(RejectReason),
+    /// A repeated hello, or anything from a client that hasn't said hello yet.
+    Ignored,
+}
+
+/// Runs the server side of the handshake for a message received from `addr` at `now`: only a
+/// compatible hello adds a connection, and connected clients have their activity refreshed.
+pub fn admit(
+    connections: &mut HashMap<SocketAddr, Duration>,
+    addr: SocketAddr,
+    now: Duration,
+    message: Message,
+) -> Admission {
+    if let Some(last_update) = connections.get_mut(&addr) {
+        *last_update = now;
+        // a client repeats its hello until it hears back, drop the extras
+        return match message {
+            Message::Hello { .. } => Admission::Ignored,
+            message => Admission::Message(message),
+        };
+    }
+
+    match message {
+        Message::Hello { version, hash } => match check_compatibility(version, hash) {
+            Ok(()) => {
+                connections.insert(addr, now);
+                Admission::Connected
+            }
+            Err(reason) => Admission::Rejected(reason),
+        },
+        _ => Admission::Ignored,
+    }
+}
+
+/// Hash of the shape of every `Message` variant, in declaration order: variant names along with
+/// the names and types of their fields, down to the structs and enums they are made of. Peers
+/// only talk to each other when theirs match.
+pub fn protocol_hash() -> u64 {
+    // FNV-1a, as std's hashers aren't guaranteed to be stable across builds
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for shape in message_schema().values() {
+        for byte in shape.bytes().chain(std::iter::once(0)) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Names of every `Message` variant, as registered with serde.
+fn message_variants() -> &'static [&'static str] {
+    let mut variants: &'static [&'static str] = &[];
+    // serde hands the variant names to the deserializer before reading anything, so the
+    // deserializer captures them and bails
+    let _ = Message::deserialize(VariantNames(&mut variants));
+    variants
+}
+
+/// Describes every variant of `Message` and of the enums inside them, keyed by enum name and
+/// variant index.
+fn message_schema() -> BTreeMap<(&'static str, usize), String> {
+    let mut schema = Schema::default();
+    for variant in 0..message_variants().len() {
+        // every pass goes down `variant` and describes one more variant of the enums it meets
+        loop {
+            let known = schema.described.len();
+            schema.forced = Some(variant);
+            let _ = Message::deserialize(Tracer(&mut schema));
+            schema.frames.clear();
+            if schema.described.len() == known {
+                break;
+            }
+        }
+    }
+    schema.described
+}
+
+struct VariantNames<'a>(&'a mut &'static [&'static str]);
+
+/// Stops a deserializer of this module once it has seen what it was after.
+#[derive(Debug)]
+struct Halt;
+
+impl fmt::Display for Halt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("stopped inspecting the message types")
+    }
+}
+
+impl std::error::Error for Halt {}
+
+impl de::Error for Halt {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        Halt
+    }
+}
+
+impl<'de> Deserializer<'de> for VariantNames<'_> {
+    type Error = Halt;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Halt> {
+        Err(Halt)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Halt> {
+        *self.0 = variants;
+        Err(Halt)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+/// Deepest the `Tracer` nests enum variants, in case a type only ever contains itself.
+const MAX_TRACE_DEPTH: usize = 16;
+
+/// What the `Tracer` learnt about the message types so far.
+#[derive(Default)]
+struct Schema {
+    /// Description of each enum variant, written while it is being deserialized.
+    described: BTreeMap<(&'static str, usize), String>,
+    /// The variants being deserialized, innermost last, with the key their description goes
+    /// under. `None` for variants described before, which are only deserialized for a value.
+    frames: Vec<Option<(&'static str, usize)>>,
+    /// Variant the outermost enum picks on the next pass.
+    forced: Option<usize>,
+}
+
+impl Schema {
+    fn write(&mut self, token: &str) {
+        if let Some(Some(key)) = self.frames.last() {
+            let shape = self.described.get_mut(key).unwrap();
+            shape.push_str(token);
+            shape.push(' ');
+        }
+    }
+
+    /// Picks the variant of enum `name` to deserialize next: the forced one on the outermost
+    /// enum, otherwise the first one not described yet, falling back to the first one.
+    fn enter_variant(&mut self, name: &'static str, variants: &'static [&'static str]) -> usize {
+        let index = self.forced.take().unwrap_or_else(|| {
+            (0..variants.len())
+                .find(|i| !self.described.contains_key(&(name, *i)))
+                .unwrap_or(0)
+        });
+        match self.described.entry((name, index)) {
+            Entry::Occupied(_) => self.frames.push(None),
+            Entry::Vacant(entry) => {
+                entry.insert(format!("{}::{} ", name, variants[index]));
+                self.frames.push(Some((name, index)));
+            }
+        }
+        index
+    }
+}
+
+/// Deserializer that makes up a value of whatever type asks for one, and describes the types it
+/// was asked for to the `Schema`. Serde hands over the names of struct fields and enum variants
+/// along the way, so the description covers those too.
+struct Tracer<'a>(&'a mut Schema);
+
+macro_rules! trace_primitives {
+    ($($method:ident $token:literal => $visit:ident($($value:expr)?),)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Halt> {
+                self.0.write($token);
+                visitor.$visit($($value)?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Tracer<'_> {
+    type Error = Halt;
+
+    trace_primitives! {
+        deserialize_bool "bool" => visit_bool(false),
+        deserialize_i8 "i8" => visit_i8(0),
+        deserialize_i16 "i16" => visit_i16(0),
+        deserialize_i32 "i32" => visit_i32(0),
+        deserialize_i64 "i64" => visit_i64(0),
+        deserialize_i128 "i128" => visit_i128(0),
+        deserialize_u8 "u8" => visit_u8(0),
+        deserialize_u16 "u16" => visit_u16(0),
+        deserialize_u32 "u32" => visit_u32(0),
+        deserialize_u64 "u64" => visit_u64(0),
+        deserialize_u128 "u128" => visit_u128(0),
+        deserialize_f32 "f32" => visit_f32(0.),
+        deserialize_f64 "f64" => visit_f64(0.),
+        deserialize_char "char" => visit_char('\0'),
+        deserialize_str "str" => visit_str(""),
+        deserialize_string "str" => visit_str(""),
+        deserialize_bytes "bytes" => visit_bytes(&[]),
+        deserialize_byte_buf "bytes" => visit_bytes(&[]),
+        deserialize_unit "()" => visit_unit(),
+    }
+
+    /// Anything self-describing, such as an untagged enum, can't be traced.
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Halt> {
+        self.0.write("any");
+        Err(Halt)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Halt> {
+        self.0.write("option");
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Halt> {
+        self.0.write(name);
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Halt> {
+        self.0.write(name);
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Halt> {
+        self.0.write("seq");
+        // a single element describes them all
+        visit_elements(self.0, 1, None, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Halt> {
+        self.0.write(&format!("tuple{}", len));
+        visit_elements(self.0, len, None, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Halt> {
+        self.0.write(name);
+        visit_elements(self.0, len, None, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Halt> {
+        self.0.write("map");
+        Err(Halt)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Halt> {
+        self.0.write(name);
+        visit_elements(self.0, fields.len(), Some(fields), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Halt> {
+        if self.0.frames.len() >= MAX_TRACE_DEPTH {
+            return Err(Halt);
+        }
+        self.0.write(name);
+        let index = self.0.enter_variant(name, variants);
+        let value = visitor.visit_enum(Variant {
+            schema: &mut *self.0,
+            index,
+        });
+        self.0.frames.pop();
+        value
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Halt> {
+        Err(Halt)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Halt> {
+        Err(Halt)
+    }
+}
+
+/// Hands out made up elements of a sequence, tuple or struct, labelled with the struct's field
+/// names if there are any.
+struct Elements<'a> {
+    schema: &'a mut Schema,
+    remaining: usize,
+    fields: std::slice::Iter<'static, &'static str>,
+}
+
+fn visit_elements<'de, V: Visitor<'de>>(
+    schema: &mut Schema,
+    len: usize,
+    fields: Option<&'static [&'static str]>,
+    visitor: V,
+) -> Result<V::Value, Halt> {
+    schema.write("(");
+    let value = visitor.visit_seq(Elements {
+        schema: &mut *schema,
+        remaining: len,
+        fields: fields.unwrap_or_default().iter(),
+    });
+    schema.write(")");
+    value
+}
+
+impl<'de> SeqAccess<'de> for Elements<'_> {
+    type Error = Halt;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Halt> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        if let Some(field) = self.fields.next() {
+            self.schema.write(&format!("{}:", field));
+        }
+        seed.deserialize(Tracer(&mut *self.schema)).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+/// The variant of an enum picked by `Schema::enter_variant`.
+struct Variant<'a> {
+    schema: &'a mut Schema,
+    index: usize,
+}
+
+impl<'de, 'a> EnumAccess<'de> for Variant<'a> {
+    type Error = Halt;
+    type Variant = Self;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<(T::Value, Self), Halt> {
+        let index: U32Deserializer<Halt> = (self.index as u32).into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'de, 'a> VariantAccess<'de> for Variant<'a> {
+    type Error = Halt;
+
+    fn unit_variant(self) -> Result<(), Halt> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Halt> {
+        seed.deserialize(Tracer(self.schema))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Halt> {
+        visit_elements(self.schema, len, None, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Halt> {
+        visit_elements(self.schema, fields.len(), Some(fields), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_variants_are_captured() {
+        let variants = message_variants();
+        assert_eq!(variants.first(), Some(&"Heartbeat"));
+        assert!(variants.contains(&"Hello"));
+    }
+
+    #[test]
+    fn test_schema_covers_fields() {
+        let schema = message_schema();
+        assert_eq!(
+            schema.keys().filter(|(name, _)| *name == "Message").count(),
+            message_variants().len()
+        );
+        // fields of structs nested in a variant, and the variants of nested enums
+        let command = schema.values().find(|s| s.starts_with("Message::Command "));
+        assert!(command.unwrap().contains("view_tick: u64"));
+        assert!(schema.contains_key(&("MatchPhase", 3)));
+        assert!(schema.contains_key(&("RejectReason", 1)));
+    }
+
+    #[test]
+    fn test_admit() {
+        let mut connections = HashMap::new();
+        let addr = "127.0.0.1:4567".parse().unwrap();
+        let hello = || Message::Hello {
+            version: PROTOCOL_VERSION,
+            hash: protocol_hash(),
+        };
+
+        let admission = admit(&mut connections, addr, Duration::ZERO, Message::Heartbeat);
+        assert!(matches!(admission, Admission::Ignored));
+        assert!(connections.is_empty());
+
+        let admission = admit(&mut connections, addr, Duration::ZERO, hello());
+        assert!(matches!(admission, Admission::Connected));
+        let admission = admit(&mut connections, addr, Duration::from_secs(1), hello());
+        assert!(matches!(admission, Admission::Ignored));
+        let admission = admit(
+            &mut connections,
+            addr,
+            Duration::from_secs(2),
+            Message::Heartbeat,
+        );
+        assert!(matches!(admission, Admission::Message(Message::Heartbeat)));
+        assert_eq!(connections[&addr], Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_compatibility() {
+        assert_eq!(
+            check_compatibility(PROTOCOL_VERSION, protocol_hash()),
+            Ok(())
+        );
+        assert!(matches!(
+            check_compatibility(PROTOCOL_VERSION + 1, protocol_hash()),
+            Err(RejectReason::VersionMismatch { .. })
+        ));
+        assert!(matches!(
+            check_compatibility(PROTOCOL_VERSION, protocol_hash() ^ 1),
+            Err(RejectReason::HashMismatch { .. })
+        ));
+    }
+}
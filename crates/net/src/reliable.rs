@@ -0,0 +1,271 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::message::{Message, OutgoingMessage};
+
+/// How long a reliable message waits for its acknowledgement before it is sent again.
+const RESEND_INTERVAL: Duration = Duration::from_millis(200);
+/// How many times a reliable message is sent before the peer is assumed to be gone. Together
+/// with `RESEND_INTERVAL` this should be about the server's idle timeout.
+const MAX_ATTEMPTS: u32 = 25;
+/// How many messages that arrived ahead of a missing one are kept until it shows up.
+const MAX_PENDING: usize = 256;
+
+struct Unacknowledged {
+    payload: Vec<u8>,
+    /// When the message last went out, `None` until the first send.
+    last_sent: Option<Duration>,
+    attempts: u32,
+}
+
+#[derive(Default)]
+struct SendChannel {
+    next_seq: u32,
+    unacknowledged: BTreeMap<u32, Unacknowledged>,
+}
+
+/// Numbers the reliable messages queued on a `Transport`, per destination, and keeps copies of
+/// them until they are acknowledged.
+#[derive(Default)]
+pub(crate) struct ReliableSender {
+    channels: HashMap<Option<SocketAddr>, SendChannel>,
+}
+
+impl ReliableSender {
+    /// Wraps `message` in a `Message::Reliable` with the next sequence number for `destination`
+    /// and keeps a copy to send again.
+    pub fn queue(&mut self, destination: Option<SocketAddr>, message: Message) -> OutgoingMessage {
+        let channel = self.channels.entry(destination).or_default();
+        let seq = channel.next_seq;
+        channel.next_seq += 1;
+
+        let reliable = Message::Reliable {
+            seq,
+            message: Box::new(message),
+        };
+        let outgoing = match destination {
+            Some(addr) => OutgoingMessage::new_directed(addr, reliable),
+            None => OutgoingMessage::new(reliable),
+        };
+        channel.unacknowledged.insert(
+            seq,
+            Unacknowledged {
+                payload: outgoing.payload.clone(),
+                last_sent: None,
+                attempts: 1,
+            },
+        );
+        outgoing
+    }
+
+    /// Forgets the message `addr` acknowledged, whether it was sent to `addr` or, on a client, to
+    /// the connected peer.
+    pub fn acknowledge(&mut self, addr: SocketAddr, seq: u32) {
+        for destination in [Some(addr), None] {
+            if let Some(channel) = self.channels.get_mut(&destination) {
+                if channel.unacknowledged.remove(&seq).is_some() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Swaps the queued message `outgoing` for a `Message::Heartbeat` with the same sequence
+    /// number, for a message no peer would accept. Later messages then don't wait on it forever.
+    /// Returns the stand-in to send, `None` if `outgoing` isn't a queued reliable message.
+    pub fn stand_in(&mut self, outgoing: &OutgoingMessage) -> Option<OutgoingMessage> {
+        let channel = self.channels.get_mut(&outgoing.destination)?;
+        let (seq, message) = channel
+            .unacknowledged
+            .iter_mut()
+            .find(|(_, message)| message.payload == outgoing.payload)?;
+        let stand_in = Message::Reliable {
+            seq: *seq,
+            message: Box::new(Message::Heartbeat),
+        };
+        let stand_in = match outgoing.destination {
+            Some(addr) => OutgoingMessage::new_directed(addr, stand_in),
+            None => OutgoingMessage::new(stand_in),
+        };
+        message.payload = stand_in.payload.clone();
+        Some(stand_in)
+    }
+
+    /// Returns copies of the messages still unacknowledged `RESEND_INTERVAL` after they were last
+    /// sent, as of `now`. Messages sent `MAX_ATTEMPTS` times are given up on.
+    pub fn resend(&mut self, now: Duration) -> Vec<OutgoingMessage> {
+        let mut resent = Vec::new();
+        for (destination, channel) in self.channels.iter_mut() {
+            channel.unacknowledged.retain(|_, message| {
+                let last_sent = match message.last_sent {
+                    Some(last_sent) => last_sent,
+                    None => {
+                        // queued this tick, it goes out along with everything else
+                        message.last_sent = Some(now);
+                        return true;
+                    }
+                };
+                if now.saturating_sub(last_sent) < RESEND_INTERVAL {
+                    return true;
+                }
+                if message.attempts >= MAX_ATTEMPTS {
+                    return false;
+                }
+
+                message.attempts += 1;
+                message.last_sent = Some(now);
+                resent.push(OutgoingMessage {
+                    payload: message.payload.clone(),
+                    destination: *destination,
+                });
+                true
+            });
+        }
+        resent
+    }
+
+    /// Number of messages sent to `addr` that weren't acknowledged yet.
+    pub fn pending(&self, addr: SocketAddr) -> usize {
+        self.channels
+            .get(&Some(addr))
+            .map_or(0, |channel| channel.unacknowledged.len())
+    }
+
+    /// Drops everything sent to `addr`, so a new connection from it starts over.
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.channels.remove(&Some(addr));
+    }
+}
+
+#[derive(Default)]
+struct RecvChannel {
+    next_seq: u32,
+    pending: BTreeMap<u32, Message>,
+}
+
+/// Puts the reliable messages received from each peer back in order and drops duplicates.
+#[derive(Default)]
+pub(crate) struct ReliableReceiver {
+    channels: HashMap<SocketAddr, RecvChannel>,
+}
+
+impl ReliableReceiver {
+    /// Takes the reliable message `seq` from `addr` and returns the messages that are now ready
+    /// in order, none while an earlier one is missing or when `seq` was seen before. Returns
+    /// `None` when there is no room to keep it, it must not be acknowledged then so the peer
+    /// sends it again.
+    pub fn receive(
+        &mut self,
+        addr: SocketAddr,
+        seq: u32,
+        message: Message,
+    ) -> Option<Vec<Message>> {
+        let channel = self.channels.entry(addr).or_default();
+        if seq < channel.next_seq {
+            // our acknowledgement was lost, the peer needs another one
+            return Some(Vec::new());
+        }
+        if channel.pending.len() >= MAX_PENDING && !channel.pending.contains_key(&seq) {
+            return None;
+        }
+        channel.pending.insert(seq, message);
+
+        let mut ready = Vec::new();
+        while let Some(message) = channel.pending.remove(&channel.next_seq) {
+            ready.push(message);
+            channel.next_seq += 1;
+        }
+        Some(ready)
+    }
+
+    /// Drops everything received from `addr`, so a new connection from it starts over.
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.channels.remove(&addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(message: &Message) -> Option<u64> {
+        match message {
+            Message::Tick(tick) => Some(*tick),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_delivers_in_order() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut receiver = ReliableReceiver::default();
+
+        let ready = receiver.receive(addr, 1, Message::Tick(1)).unwrap();
+        assert!(ready.is_empty());
+        let ready = receiver.receive(addr, 0, Message::Tick(0)).unwrap();
+        assert_eq!(
+            ready.iter().map(tick).collect::<Vec<_>>(),
+            [Some(0), Some(1)]
+        );
+
+        // a resend of something already delivered is acknowledged again but not handed on
+        assert!(receiver
+            .receive(addr, 1, Message::Tick(1))
+            .unwrap()
+            .is_empty());
+        let ready = receiver.receive(addr, 2, Message::Tick(2)).unwrap();
+        assert_eq!(ready.iter().map(tick).collect::<Vec<_>>(), [Some(2)]);
+    }
+
+    #[test]
+    fn test_resends_until_acknowledged() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut sender = ReliableSender::default();
+        let first = sender.queue(Some(addr), Message::Heartbeat);
+        sender.queue(Some(addr), Message::Heartbeat);
+
+        assert!(sender.resend(Duration::ZERO).is_empty());
+        assert!(sender.resend(RESEND_INTERVAL / 2).is_empty());
+        assert_eq!(sender.resend(RESEND_INTERVAL).len(), 2);
+
+        sender.acknowledge(addr, 1);
+        let resent = sender.resend(RESEND_INTERVAL * 2);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].payload, first.payload);
+        assert_eq!(resent[0].destination, Some(addr));
+    }
+
+    #[test]
+    fn test_stand_in() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut sender = ReliableSender::default();
+        let oversized = sender.queue(Some(addr), Message::Kicked("x".repeat(100)));
+
+        let stand_in = sender.stand_in(&oversized).unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&stand_in.payload).unwrap(),
+            Message::Reliable { seq: 0, message } if matches!(*message, Message::Heartbeat)
+        ));
+        // resent in its place
+        sender.resend(Duration::ZERO);
+        let resent = sender.resend(RESEND_INTERVAL);
+        assert_eq!(resent[0].payload, stand_in.payload);
+        // an unreliable message has nothing to stand in for
+        assert!(sender
+            .stand_in(&OutgoingMessage::new(Message::Heartbeat))
+            .is_none());
+    }
+
+    #[test]
+    fn test_gives_up_eventually() {
+        let mut sender = ReliableSender::default();
+        sender.queue(None, Message::Heartbeat);
+
+        let mut sent = 1;
+        for i in 0..MAX_ATTEMPTS * 2 {
+            sent += sender.resend(RESEND_INTERVAL * i).len() as u32;
+        }
+        assert_eq!(sent, MAX_ATTEMPTS);
+    }
+}
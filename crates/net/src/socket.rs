@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+
+use bevy::utils::Instant;
+
+/// A datagram socket the network systems send and receive through. It must be non-blocking:
+/// `recv_from` returns `io::ErrorKind::WouldBlock` once no datagrams are left to read.
+pub trait DatagramSocket: Send + Sync + 'static {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    /// Like `recv_from`, along with when the datagram arrived for sockets that know better than
+    /// the caller.
+    fn recv_with_arrival(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<Instant>)> {
+        let (len, addr) = self.recv_from(buf)?;
+        Ok((len, addr, None))
+    }
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    /// Sends to the peer the socket is connected to.
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        UdpSocket::send(self, buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::peer_addr(self)
+    }
+}
+
+/// Several sockets used as one, so a server can take clients over different transports at once.
+/// Replies go out through the socket a peer last sent from, anything else through the first.
+pub struct SocketSet {
+    sockets: Vec<Box<dyn DatagramSocket>>,
+    routes: Mutex<HashMap<SocketAddr, usize>>,
+}
+
+impl SocketSet {
+    pub fn new(sockets: Vec<Box<dyn DatagramSocket>>) -> Self {
+        assert!(
+            !sockets.is_empty(),
+            "a socket set needs at least one socket"
+        );
+        Self {
+            sockets,
+            routes: Default::default(),
+        }
+    }
+}
+
+impl DatagramSocket for SocketSet {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut error = None;
+        for (i, socket) in self.sockets.iter().enumerate() {
+            match socket.recv_from(buf) {
+                Ok((len, addr)) => {
+                    self.routes.lock().unwrap().insert(addr, i);
+                    return Ok((len, addr));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // keep reading the others, the error is only reported once they are empty
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| io::ErrorKind::WouldBlock.into()))
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let route = self.routes.lock().unwrap().get(&addr).copied();
+        self.sockets[route.unwrap_or(0)].send_to(buf, addr)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.sockets[0].send(buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.sockets[0].peer_addr()
+    }
+}
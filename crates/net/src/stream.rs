@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+
+use crate::socket::DatagramSocket;
+
+/// How many bytes may wait to be written to a stream before further payloads are dropped, as a
+/// datagram would be on a congested network.
+const MAX_BACKLOG: usize = 64 * 1024;
+
+/// Accepts TCP connections for networks that block UDP. Each payload is framed with its length
+/// as a little-endian `u16`, the stream takes care of delivery and ordering.
+pub struct StreamListener {
+    listener: TcpListener,
+    connections: Mutex<HashMap<SocketAddr, Connection>>,
+}
+
+impl StreamListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            connections: Default::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl DatagramSocket for StreamListener {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut connections = self.connections.lock().unwrap();
+        while let Ok((stream, addr)) = self.listener.accept() {
+            if let Ok(connection) = Connection::new(stream) {
+                connections.insert(addr, connection);
+            }
+        }
+
+        let mut received = None;
+        let mut closed = Vec::new();
+        for (addr, connection) in connections.iter_mut() {
+            match connection.recv(buf) {
+                Ok(Some(len)) => {
+                    received = Some((len, *addr));
+                    break;
+                }
+                Ok(None) => {}
+                // the peer is gone, the server notices once it times out
+                Err(_) => closed.push(*addr),
+            }
+        }
+        for addr in closed {
+            connections.remove(&addr);
+        }
+
+        received.ok_or_else(|| io::ErrorKind::WouldBlock.into())
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get_mut(&addr) {
+            Some(connection) => connection.send(buf),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// A TCP connection to a server's `StreamListener`.
+pub struct StreamClient {
+    connection: Mutex<Connection>,
+    peer: SocketAddr,
+}
+
+impl StreamClient {
+    /// Connects to the listener at `addr`, blocking until the connection is established.
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            connection: Mutex::new(Connection::new(TcpStream::connect(addr)?)?),
+            peer: addr,
+        })
+    }
+}
+
+impl DatagramSocket for StreamClient {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.connection.lock().unwrap().recv(buf)? {
+            Some(len) => Ok((len, self.peer)),
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if addr != self.peer {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.send(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.connection.lock().unwrap().send(buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+/// A non-blocking stream with the bytes of partially read and written frames.
+struct Connection {
+    stream: TcpStream,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+        })
+    }
+
+    /// Reads the next whole frame into `buf`, truncating it like a datagram that doesn't fit.
+    /// Returns `Ok(None)` while no whole frame has arrived and an error once the connection is
+    /// gone.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        self.flush()?;
+        loop {
+            if let Some(len) = self.take_frame(buf) {
+                return Ok(Some(len));
+            }
+
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.incoming.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn take_frame(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let header = self.incoming.get(..2)?;
+        let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let payload = self.incoming.get(2..2 + len)?;
+
+        let copied = len.min(buf.len());
+        buf[..copied].copy_from_slice(&payload[..copied]);
+        self.incoming.drain(..2 + len);
+        Some(copied)
+    }
+
+    fn send(&mut self, payload: &[u8]) -> io::Result<usize> {
+        let len = u16::try_from(payload.len())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        if self.outgoing.len() + 2 + payload.len() <= MAX_BACKLOG {
+            self.outgoing.extend_from_slice(&len.to_le_bytes());
+            self.outgoing.extend_from_slice(payload);
+        }
+        self.flush()?;
+        Ok(payload.len())
+    }
+
+    /// Writes as much of the outgoing frames as the stream takes without blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_util::recv;
+
+    #[test]
+    fn test_stream_round_trip() {
+        let listener = StreamListener::bind("127.0.0.1:0").unwrap();
+        let client = StreamClient::connect(listener.local_addr().unwrap()).unwrap();
+        client.send(b"hello").unwrap();
+        client.send(b"").unwrap();
+        client.send(b"again").unwrap();
+
+        let (payload, client_addr) = recv(&listener);
+        assert_eq!(payload, b"hello");
+        assert_eq!(recv(&listener).0, b"");
+        assert_eq!(recv(&listener).0, b"again");
+
+        listener.send_to(b"welcome", client_addr).unwrap();
+        assert_eq!(recv(&client).0, b"welcome");
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let listener = StreamListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        let mut buf = [0; 64];
+        stream.write_all(&[3, 0, b'a']).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            listener.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        stream.write_all(&[b'b', b'c']).unwrap();
+        assert_eq!(recv(&listener).0, b"abc");
+    }
+}
@@ -1,18 +1,45 @@
 use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use bevy::prelude::*;
+use bevy::utils::Instant;
 
+use crate::codec::{self, Codec, MAX_PAYLOAD_LEN};
+use crate::protocol::{self, Admission};
 use crate::{message::Message, HeartbeatTimer, Socket};
 
 use super::{events::NetworkEvent, transport::Transport, NetworkResource};
 
-pub fn client_recv_packet_system(socket: Res<Socket>, mut events: EventWriter<NetworkEvent>) {
+pub fn client_recv_packet_system(
+    time: Res<Time>,
+    socket: Res<Socket>,
+    mut events: EventWriter<NetworkEvent>,
+    mut net: ResMut<NetworkResource>,
+    mut transport: ResMut<Transport>,
+) {
     loop {
-        let mut buf = [0; 512];
-        match socket.recv_from(&mut buf) {
-            Ok((recv_len, address)) => {
-                if let Ok(message) = serde_json::from_slice::<'_, Message>(&buf[..recv_len]) {
-                    events.send(NetworkEvent::Message(address, message));
+        let mut buf = [0; MAX_PAYLOAD_LEN + 1];
+        match socket.recv_with_arrival(&mut buf) {
+            Ok((recv_len, address, arrived)) => {
+                let now = arrival_time(&time, arrived);
+                match codec::decode(&buf[..recv_len]) {
+                    Ok(Message::Rejected(reason)) => {
+                        events.send(NetworkEvent::Rejected(address, reason));
+                    }
+                    Ok(message) => {
+                        // hearing back means the server accepted our hello
+                        net.connections.insert(address, now);
+                        net.count_received(address, recv_len);
+                        for message in deliver(&mut net, &mut transport, address, message) {
+                            events.send(NetworkEvent::Message(address, message));
+                        }
+                    }
+                    Err(e) => {
+                        net.stats.malformed += 1;
+                        debug!("malformed payload from {}: {}", address, e);
+                        events.send(NetworkEvent::Malformed(address, e));
+                    }
                 }
             }
             Err(e) => {
@@ -31,19 +58,53 @@ pub fn server_recv_packet_system(
     socket: Res<Socket>,
     mut events: EventWriter<NetworkEvent>,
     mut net: ResMut<NetworkResource>,
+    mut transport: ResMut<Transport>,
 ) {
     loop {
-        let mut buf = [0; 512];
-        match socket.recv_from(&mut buf) {
-            Ok((recv_len, address)) => {
-                if net.connections.insert(address, time.elapsed()).is_none() {
-                    // connection established
-                    events.send(NetworkEvent::Connected(address));
+        let mut buf = [0; MAX_PAYLOAD_LEN + 1];
+        match socket.recv_with_arrival(&mut buf) {
+            Ok((recv_len, address, arrived)) => {
+                if net.banned.contains(&address.ip()) {
+                    continue;
+                }
+                let now = arrival_time(&time, arrived);
+                let message = match codec::decode(&buf[..recv_len]) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        net.stats.malformed += 1;
+                        debug!("malformed payload from {}: {}", address, e);
+                        events.send(NetworkEvent::Malformed(address, e));
+                        continue;
+                    }
+                };
+                // taken from anyone, so a kicked client can still confirm it was told why
+                if let Message::Ack(seq) = message {
+                    net.count_received(address, recv_len);
+                    transport.acknowledge(address, seq);
+                    continue;
                 }
 
-                if let Ok(message) = serde_json::from_slice::<'_, Message>(&buf[..recv_len]) {
-                    events.send(NetworkEvent::Message(address, message));
+                match protocol::admit(&mut net.connections, address, now, message) {
+                    Admission::Message(message) => {
+                        for message in deliver(&mut net, &mut transport, address, message) {
+                            events.send(NetworkEvent::Message(address, message));
+                        }
+                    }
+                    Admission::Connected => {
+                        net.peers.remove(&address);
+                        net.reliable.remove(address);
+                        transport.forget_peer(address);
+                        events.send(NetworkEvent::Connected(address));
+                    }
+                    Admission::Rejected(reason) => {
+                        net.stats.rejected += 1;
+                        warn!("rejected {}: {}", address, reason);
+                        transport.send_to(address, Message::Rejected(reason.clone()));
+                        events.send(NetworkEvent::Rejected(address, reason));
+                    }
+                    Admission::Ignored => debug!("ignoring a message from {}", address),
                 }
+                net.count_received(address, recv_len);
             }
             Err(e) => {
                 if e.kind() != io::ErrorKind::WouldBlock {
@@ -56,20 +117,91 @@ pub fn server_recv_packet_system(
     }
 }
 
+/// Unwraps what the reliability layer added to a message from `addr`: acknowledgements are
+/// consumed and reliable messages are acknowledged, then handed on once every earlier one has
+/// been. Anything else is handed on as is.
+fn deliver(
+    net: &mut NetworkResource,
+    transport: &mut Transport,
+    addr: SocketAddr,
+    message: Message,
+) -> Vec<Message> {
+    match message {
+        Message::Ack(seq) => {
+            transport.acknowledge(addr, seq);
+            Vec::new()
+        }
+        Message::Reliable { seq, message } => match net.reliable.receive(addr, seq, *message) {
+            Some(ready) => {
+                transport.send_to(addr, Message::Ack(seq));
+                ready
+            }
+            None => Vec::new(),
+        },
+        message => vec![message],
+    }
+}
+
+/// When a datagram arrived, as time since startup like `Time::elapsed`. Never later than the
+/// current frame, so it can be compared against it.
+fn arrival_time(time: &Time, arrived: Option<Instant>) -> Duration {
+    match arrived {
+        Some(arrived) => arrived
+            .saturating_duration_since(time.startup())
+            .min(time.elapsed()),
+        None => time.elapsed(),
+    }
+}
+
 pub fn send_packet_system(
     socket: Res<Socket>,
+    codec: Res<Codec>,
     mut events: EventWriter<NetworkEvent>,
+    mut net: ResMut<NetworkResource>,
     mut transport: ResMut<Transport>,
 ) {
     let messages = transport.drain_messages_to_send(|_| true);
     for message in messages {
+        let mut payload = codec.encode(&message.payload);
+        let message = if payload.len() > MAX_PAYLOAD_LEN {
+            // every peer would drop it, and resending it wouldn't change that
+            let error = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "not sending a {} byte payload, peers won't accept more than {}",
+                    payload.len(),
+                    MAX_PAYLOAD_LEN
+                ),
+            );
+            warn!("{}", error);
+            net.stats.oversized += 1;
+            let stand_in = transport.stand_in(&message);
+            events.send(NetworkEvent::SendError(error, message));
+            match stand_in {
+                Some(stand_in) => {
+                    payload = codec.encode(&stand_in.payload);
+                    stand_in
+                }
+                None => continue,
+            }
+        } else {
+            message
+        };
+        net.stats.serialized_bytes += message.payload.len() as u64;
+        net.stats.sent_bytes += payload.len() as u64;
+
         let result = match message.destination {
-            Some(addr) => socket.send_to(&message.payload, addr),
-            None => socket.send(&message.payload),
+            Some(addr) => socket.send_to(&payload, addr),
+            None => socket.send(&payload),
         };
 
-        if let Err(e) = result {
-            events.send(NetworkEvent::SendError(e, message));
+        match result {
+            Ok(_) => {
+                if let Some(addr) = message.destination.or_else(|| socket.peer_addr().ok()) {
+                    net.count_sent(addr, payload.len());
+                }
+            }
+            Err(e) => events.send(NetworkEvent::SendError(e, message)),
         }
     }
 }
@@ -77,24 +209,61 @@ pub fn send_packet_system(
 pub fn idle_timeout_system(
     time: Res<Time>,
     mut net: ResMut<NetworkResource>,
+    mut transport: ResMut<Transport>,
     mut events: EventWriter<NetworkEvent>,
 ) {
     let idle_timeout = net.idle_timeout.clone();
-    net.connections.retain(|addr, last_update| {
+    let NetworkResource {
+        connections,
+        peers,
+        reliable,
+        kicked,
+        ..
+    } = &mut *net;
+    for addr in kicked.drain(..) {
+        // the reason is still being resent, so only forget what was received
+        reliable.remove(addr);
+        events.send(NetworkEvent::Disconnected(addr));
+    }
+    connections.retain(|addr, last_update| {
         let reached_idle_timeout = time.elapsed() - *last_update > idle_timeout;
         if reached_idle_timeout {
+            peers.remove(addr);
+            reliable.remove(*addr);
+            transport.forget_peer(*addr);
             events.send(NetworkEvent::Disconnected(*addr));
         }
         !reached_idle_timeout
     });
 }
 
+pub fn resend_reliable_system(time: Res<Time>, mut transport: ResMut<Transport>) {
+    transport.resend_unacknowledged(time.elapsed());
+}
+
+pub fn client_hello_system(mut transport: ResMut<Transport>) {
+    transport.send(hello());
+}
+
 pub fn auto_heartbeat_system(
     time: Res<Time>,
+    net: Res<NetworkResource>,
     mut timer: ResMut<HeartbeatTimer>,
     mut transport: ResMut<Transport>,
 ) {
     if timer.0.tick(time.delta()).just_finished() {
-        transport.send(Message::Heartbeat);
+        // keep saying hello until the server answers, in case it was lost
+        if net.connections.is_empty() {
+            transport.send(hello());
+        } else {
+            transport.send(Message::Heartbeat);
+        }
+    }
+}
+
+fn hello() -> Message {
+    Message::Hello {
+        version: protocol::PROTOCOL_VERSION,
+        hash: protocol::protocol_hash(),
     }
 }
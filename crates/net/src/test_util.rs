@@ -0,0 +1,24 @@
+//! Helpers shared by the tests of the socket implementations.
+
+use std::io;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::socket::DatagramSocket;
+
+/// Polls `socket` until a payload arrives, or gives up after a few seconds.
+pub fn recv(socket: &impl DatagramSocket) -> (Vec<u8>, SocketAddr) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut buf = [0; 64];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, addr)) => return (buf[..len].to_vec(), addr),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                assert!(Instant::now() < deadline, "timed out waiting for a payload");
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError};
+
+use crate::codec::MAX_PAYLOAD_LEN;
+use crate::socket::DatagramSocket;
+
+/// How long the I/O thread sleeps when there was nothing to send or receive.
+const IDLE_POLL: Duration = Duration::from_millis(1);
+/// How many datagrams may wait in either direction before more are dropped, as a full socket
+/// buffer would.
+const QUEUE_LEN: usize = 4096;
+
+struct Received {
+    result: io::Result<(Vec<u8>, SocketAddr)>,
+    arrived: Instant,
+}
+
+struct Outgoing {
+    payload: Vec<u8>,
+    destination: Option<SocketAddr>,
+}
+
+/// Moves a socket onto its own thread, which sends and receives regardless of how long frames
+/// take and stamps every datagram with when it arrived. Only it knows the arrival times, so
+/// sockets wrapping it have to pass `recv_with_arrival` on.
+pub struct ThreadedSocket {
+    incoming: Receiver<Received>,
+    outgoing: Sender<Outgoing>,
+    peer: Option<SocketAddr>,
+}
+
+impl ThreadedSocket {
+    /// Spawns the I/O thread, which runs until the returned socket is dropped.
+    pub fn spawn(socket: impl DatagramSocket) -> io::Result<Self> {
+        let (incoming_tx, incoming) = crossbeam_channel::bounded(QUEUE_LEN);
+        let (outgoing, outgoing_rx) = crossbeam_channel::bounded(QUEUE_LEN);
+        let peer = socket.peer_addr().ok();
+        thread::Builder::new()
+            .name("net io".into())
+            .spawn(move || io_loop(socket, incoming_tx, outgoing_rx))?;
+
+        Ok(Self {
+            incoming,
+            outgoing,
+            peer,
+        })
+    }
+
+    fn queue(&self, payload: &[u8], destination: Option<SocketAddr>) -> io::Result<usize> {
+        let outgoing = Outgoing {
+            payload: payload.to_vec(),
+            destination,
+        };
+        match self.outgoing.try_send(outgoing) {
+            // a full queue drops the payload like a full socket buffer would
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(payload.len()),
+            Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl DatagramSocket for ThreadedSocket {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_with_arrival(buf)
+            .map(|(len, addr, _)| (len, addr))
+    }
+
+    fn recv_with_arrival(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<Instant>)> {
+        match self.incoming.try_recv() {
+            Ok(Received { result, arrived }) => {
+                let (payload, addr) = result?;
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                Ok((len, addr, Some(arrived)))
+            }
+            Err(TryRecvError::Empty) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TryRecvError::Disconnected) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.queue(buf, Some(addr))
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.queue(buf, None)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer.ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+fn io_loop(socket: impl DatagramSocket, incoming: Sender<Received>, outgoing: Receiver<Outgoing>) {
+    loop {
+        let mut busy = false;
+
+        loop {
+            match outgoing.try_recv() {
+                Ok(message) => {
+                    busy = true;
+                    let result = match message.destination {
+                        Some(addr) => socket.send_to(&message.payload, addr),
+                        None => socket.send(&message.payload),
+                    };
+                    if let Err(e) = result {
+                        warn!("could not send a payload: {}", e);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                // the socket was dropped
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        let mut buf = [0; MAX_PAYLOAD_LEN + 1];
+        let result = match socket.recv_from(&mut buf) {
+            Ok((len, addr)) => Ok((buf[..len].to_vec(), addr)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if !busy {
+                    thread::sleep(IDLE_POLL);
+                }
+                continue;
+            }
+            Err(e) => {
+                thread::sleep(IDLE_POLL);
+                Err(e)
+            }
+        };
+        let received = Received {
+            result,
+            arrived: Instant::now(),
+        };
+        match incoming.try_send(received) {
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    #[test]
+    fn test_threaded_round_trip() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_nonblocking(true).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let server = ThreadedSocket::spawn(server).unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server_addr).unwrap();
+        let sent = Instant::now();
+        client.send(b"hello").unwrap();
+
+        let deadline = sent + Duration::from_secs(5);
+        let mut buf = [0; 64];
+        let (len, client_addr, arrived) = loop {
+            match server.recv_with_arrival(&mut buf) {
+                Ok(received) => break received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "timed out waiting for a payload");
+                    thread::sleep(IDLE_POLL);
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(&buf[..len], b"hello");
+        assert!(arrived.unwrap() >= sent);
+
+        server.send_to(b"welcome", client_addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"welcome");
+    }
+}
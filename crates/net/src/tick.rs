@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use bevy::ecs::schedule::ShouldRun;
+use bevy::prelude::*;
+
+/// Defines how many network ticks run per second by default.
+const DEFAULT_TICK_RATE: f64 = 30.;
+/// Defines how many ticks a single long frame may make up for by default.
+const DEFAULT_MAX_CATCH_UP: u32 = 4;
+
+/// How often the network stages run, independent of how fast frames are rendered or the
+/// `ScheduleRunnerSettings` of a headless app. Insert it before adding a network plugin to
+/// change the defaults.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct NetworkTickSettings {
+    /// Network ticks per second.
+    pub rate: f64,
+    /// Most ticks a single frame may count after a hitch, the rest of the backlog is dropped so
+    /// a slow peer doesn't fall further and further behind.
+    pub max_catch_up: u32,
+}
+
+impl NetworkTickSettings {
+    pub fn step(&self) -> Duration {
+        Duration::from_secs_f64(1. / self.rate)
+    }
+}
+
+impl Default for NetworkTickSettings {
+    fn default() -> Self {
+        Self {
+            rate: DEFAULT_TICK_RATE,
+            max_catch_up: DEFAULT_MAX_CATCH_UP,
+        }
+    }
+}
+
+/// Accumulates frame time into fixed network ticks. The network stages run once for every tick
+/// due, and not at all on frames without one: received messages are handed to gameplay at the
+/// start of such a frame and everything queued on the `Transport` goes out at its end.
+#[derive(Resource, Default, Debug)]
+pub struct NetworkTick {
+    /// Ticks since startup.
+    pub tick: u64,
+    /// Ticks due this frame, more than one when catching up after a long frame.
+    pub due: u32,
+    accumulator: Duration,
+}
+
+impl NetworkTick {
+    /// Whether the network stages run this frame.
+    pub fn just_ticked(&self) -> bool {
+        self.due > 0
+    }
+
+    /// Counts the ticks that fit in the time accumulated so far plus `delta`.
+    pub fn advance(&mut self, delta: Duration, settings: &NetworkTickSettings) {
+        let step = settings.step();
+        self.accumulator += delta;
+        self.due = 0;
+        while self.accumulator >= step {
+            self.accumulator -= step;
+            if self.due == settings.max_catch_up {
+                // too far behind to catch up, start over from the current frame
+                self.accumulator = Duration::ZERO;
+                break;
+            }
+            self.due += 1;
+        }
+        self.tick += self.due as u64;
+    }
+}
+
+pub fn advance_network_tick_system(
+    time: Res<Time>,
+    settings: Res<NetworkTickSettings>,
+    mut tick: ResMut<NetworkTick>,
+) {
+    tick.advance(time.delta(), &settings);
+}
+
+/// Runs a network stage once for every tick due this frame. `ran` counts the runs so far, every
+/// stage has its own.
+pub fn network_tick_criteria(tick: Res<NetworkTick>, mut ran: Local<u32>) -> ShouldRun {
+    if *ran < tick.due {
+        *ran += 1;
+        ShouldRun::YesAndCheckAgain
+    } else {
+        *ran = 0;
+        ShouldRun::No
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accumulates_ticks() {
+        let settings = NetworkTickSettings {
+            rate: 10.,
+            max_catch_up: 3,
+        };
+        let mut tick = NetworkTick::default();
+
+        tick.advance(Duration::from_millis(60), &settings);
+        assert!(!tick.just_ticked());
+        tick.advance(Duration::from_millis(60), &settings);
+        assert_eq!((tick.due, tick.tick), (1, 1));
+        tick.advance(Duration::from_millis(20), &settings);
+        assert!(!tick.just_ticked());
+
+        // along with the 40 ms carried over, this makes up for two ticks
+        tick.advance(Duration::from_millis(160), &settings);
+        assert_eq!((tick.due, tick.tick), (2, 3));
+    }
+
+    #[test]
+    fn test_limits_catch_up() {
+        let settings = NetworkTickSettings {
+            rate: 10.,
+            max_catch_up: 3,
+        };
+        let mut tick = NetworkTick::default();
+
+        tick.advance(Duration::from_secs(2), &settings);
+        assert_eq!((tick.due, tick.tick), (3, 3));
+        // the backlog was dropped rather than worked off over the following frames
+        tick.advance(Duration::from_millis(50), &settings);
+        assert!(!tick.just_ticked());
+    }
+
+    #[derive(Resource, Default)]
+    struct Runs(u32);
+
+    #[test]
+    fn test_stage_runs_once_per_tick() {
+        let mut app = App::new();
+        app.init_resource::<Runs>()
+            .init_resource::<NetworkTick>()
+            .add_stage(
+                "network",
+                SystemStage::parallel()
+                    .with_run_criteria(network_tick_criteria)
+                    .with_system(|mut runs: ResMut<Runs>| runs.0 += 1),
+            );
+
+        // a long frame makes up for the ticks it missed
+        app.world.resource_mut::<NetworkTick>().due = 3;
+        app.update();
+        assert_eq!(app.world.resource::<Runs>().0, 3);
+
+        app.world.resource_mut::<NetworkTick>().due = 0;
+        app.update();
+        assert_eq!(app.world.resource::<Runs>().0, 3);
+
+        app.world.resource_mut::<NetworkTick>().due = 1;
+        app.update();
+        assert_eq!(app.world.resource::<Runs>().0, 4);
+    }
+}
@@ -1,8 +1,9 @@
-use std::{collections::VecDeque, net::SocketAddr};
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
 
 use bevy::prelude::Resource;
 
 use crate::message::Message;
+use crate::reliable::ReliableSender;
 
 use super::message::OutgoingMessage;
 
@@ -11,6 +12,7 @@ use super::message::OutgoingMessage;
 #[derive(Resource)]
 pub struct Transport {
     messages: VecDeque<OutgoingMessage>,
+    reliable: ReliableSender,
 }
 
 impl Transport {
@@ -19,6 +21,7 @@ impl Transport {
     pub fn new() -> Self {
         Self {
             messages: VecDeque::new(),
+            reliable: ReliableSender::default(),
         }
     }
 
@@ -34,6 +37,68 @@ impl Transport {
         self.messages.push_back(message);
     }
 
+    /// Queues a copy of `message` for each of the given addresses, e.g. every live connection in
+    /// `NetworkResource::connections`.
+    pub fn broadcast<'a>(
+        &mut self,
+        addrs: impl IntoIterator<Item = &'a SocketAddr>,
+        message: Message,
+    ) {
+        for addr in addrs {
+            self.send_to(*addr, message.clone());
+        }
+    }
+
+    /// Like `send`, but the message is sent again until the peer acknowledges it, and the peer
+    /// hands reliable messages on in the order they were sent.
+    pub fn send_reliable(&mut self, message: Message) {
+        let message = self.reliable.queue(None, message);
+        self.messages.push_back(message);
+    }
+
+    /// Like `send_to`, with the guarantees of `send_reliable`.
+    pub fn send_reliable_to(&mut self, addr: SocketAddr, message: Message) {
+        let message = self.reliable.queue(Some(addr), message);
+        self.messages.push_back(message);
+    }
+
+    /// Like `broadcast`, with the guarantees of `send_reliable`.
+    pub fn broadcast_reliable<'a>(
+        &mut self,
+        addrs: impl IntoIterator<Item = &'a SocketAddr>,
+        message: Message,
+    ) {
+        for addr in addrs {
+            self.send_reliable_to(*addr, message.clone());
+        }
+    }
+
+    /// Number of reliable messages to `addr` still waiting for their acknowledgement.
+    #[must_use]
+    pub fn pending_reliable(&self, addr: SocketAddr) -> usize {
+        self.reliable.pending(addr)
+    }
+
+    pub(crate) fn acknowledge(&mut self, addr: SocketAddr, seq: u32) {
+        self.reliable.acknowledge(addr, seq);
+    }
+
+    /// Queues the reliable messages that have waited too long for their acknowledgement again.
+    pub(crate) fn resend_unacknowledged(&mut self, now: Duration) {
+        let resent = self.reliable.resend(now);
+        self.messages.extend(resent);
+    }
+
+    /// Stands a heartbeat in for `message` if it is reliable, see `ReliableSender::stand_in`.
+    pub(crate) fn stand_in(&mut self, message: &OutgoingMessage) -> Option<OutgoingMessage> {
+        self.reliable.stand_in(message)
+    }
+
+    /// Stops resending reliable messages to `addr`.
+    pub(crate) fn forget_peer(&mut self, addr: SocketAddr) {
+        self.reliable.remove(addr);
+    }
+
     /// Returns true if there are messages enqueued to be sent.
     #[must_use]
     pub fn has_messages(&self) -> bool {
@@ -72,6 +137,7 @@ impl Default for Transport {
     fn default() -> Self {
         Self {
             messages: VecDeque::new(),
+            reliable: ReliableSender::default(),
         }
     }
 }
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::{Message as Frame, WebSocket};
+
+use crate::socket::DatagramSocket;
+
+type Handshake = ServerHandshake<TcpStream, NoCallback>;
+
+/// Accepts WebSocket connections, such as from the browser build, and exchanges payloads with
+/// them as binary frames, one payload per frame.
+pub struct WebSocketListener {
+    listener: TcpListener,
+    peers: Mutex<Peers>,
+}
+
+#[derive(Default)]
+struct Peers {
+    handshakes: Vec<MidHandshake<Handshake>>,
+    sockets: HashMap<SocketAddr, WebSocket<TcpStream>>,
+}
+
+impl WebSocketListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            peers: Default::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Peers {
+    /// Takes new connections and moves their handshakes along as far as they go without blocking.
+    fn accept(&mut self, listener: &TcpListener) {
+        while let Ok((stream, _)) = listener.accept() {
+            if stream.set_nonblocking(true).is_ok() && stream.set_nodelay(true).is_ok() {
+                self.handshake(tungstenite::accept(stream));
+            }
+        }
+        for handshake in std::mem::take(&mut self.handshakes) {
+            self.handshake(handshake.handshake());
+        }
+    }
+
+    fn handshake(&mut self, result: Result<WebSocket<TcpStream>, HandshakeError<Handshake>>) {
+        match result {
+            Ok(socket) => {
+                if let Ok(addr) = socket.get_ref().peer_addr() {
+                    self.sockets.insert(addr, socket);
+                }
+            }
+            Err(HandshakeError::Interrupted(handshake)) => self.handshakes.push(handshake),
+            Err(HandshakeError::Failure(_)) => {}
+        }
+    }
+}
+
+impl DatagramSocket for WebSocketListener {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut peers = self.peers.lock().unwrap();
+        peers.accept(&self.listener);
+
+        let mut received = None;
+        let mut closed = Vec::new();
+        for (addr, socket) in peers.sockets.iter_mut() {
+            match read_frame(socket, buf) {
+                Ok(Some(len)) => {
+                    received = Some((len, *addr));
+                    break;
+                }
+                Ok(None) => {}
+                // the peer is gone, the server notices once it times out
+                Err(_) => closed.push(*addr),
+            }
+        }
+        for addr in closed {
+            peers.sockets.remove(&addr);
+        }
+
+        received.ok_or_else(|| io::ErrorKind::WouldBlock.into())
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.sockets.get_mut(&addr) {
+            Some(socket) => write_frame(socket, buf),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// A WebSocket connection to a server's `WebSocketListener`, for clients that can't use UDP.
+pub struct WebSocketClient {
+    socket: Mutex<WebSocket<TcpStream>>,
+    peer: SocketAddr,
+}
+
+impl WebSocketClient {
+    /// Connects to the listener at `addr`, blocking until the handshake completes.
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let (socket, _) = tungstenite::client(format!("ws://{}/", addr), stream)
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e.to_string()))?;
+        socket.get_ref().set_nonblocking(true)?;
+        Ok(Self {
+            socket: Mutex::new(socket),
+            peer: addr,
+        })
+    }
+}
+
+impl DatagramSocket for WebSocketClient {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match read_frame(&mut self.socket.lock().unwrap(), buf)? {
+            Some(len) => Ok((len, self.peer)),
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if addr != self.peer {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.send(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        write_frame(&mut self.socket.lock().unwrap(), buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+/// Reads the next binary frame into `buf`, truncating it like a datagram that doesn't fit.
+/// Returns `Ok(None)` when nothing is waiting and an error once the connection is gone.
+fn read_frame(socket: &mut WebSocket<TcpStream>, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    loop {
+        match socket.read_message() {
+            Ok(Frame::Binary(payload)) => {
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                return Ok(Some(len));
+            }
+            // pings are answered by tungstenite and nothing else carries payloads
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(None)
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    e.to_string(),
+                ))
+            }
+        }
+    }
+}
+
+fn write_frame(socket: &mut WebSocket<TcpStream>, buf: &[u8]) -> io::Result<usize> {
+    match socket.write_message(Frame::Binary(buf.to_vec())) {
+        Ok(()) => Ok(buf.len()),
+        // the frame is queued and goes out with the next read or write
+        Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::test_util::recv;
+
+    #[test]
+    fn test_websocket_round_trip() {
+        let listener = WebSocketListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let client = WebSocketClient::connect(addr).unwrap();
+            client.send(b"hello").unwrap();
+            let (payload, from) = recv(&client);
+            assert_eq!((payload.as_slice(), from), (&b"welcome"[..], addr));
+        });
+
+        let (payload, client_addr) = recv(&listener);
+        assert_eq!(payload, b"hello");
+        listener.send_to(b"welcome", client_addr).unwrap();
+        client.join().unwrap();
+    }
+}
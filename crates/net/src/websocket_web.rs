@@ -0,0 +1,94 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+use crate::socket::DatagramSocket;
+
+/// A browser WebSocket connection to a server's `WebSocketListener`, one payload per binary
+/// message.
+pub struct WebSocketClient {
+    socket: WebSocket,
+    received: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    peer: SocketAddr,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+}
+
+// the browser runs the app and every callback on a single thread
+unsafe impl Send for WebSocketClient {}
+unsafe impl Sync for WebSocketClient {}
+
+impl WebSocketClient {
+    /// Starts connecting to the listener at `addr`. The connection opens in the background,
+    /// anything sent before then is dropped like a lost datagram.
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let socket = WebSocket::new(&format!("ws://{}/", addr)).map_err(js_error)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let received = Rc::new(RefCell::new(VecDeque::new()));
+        let queue = received.clone();
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            if let Ok(buffer) = event.data().dyn_into::<ArrayBuffer>() {
+                queue
+                    .borrow_mut()
+                    .push_back(Uint8Array::new(&buffer).to_vec());
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        Ok(Self {
+            socket,
+            received,
+            peer: addr,
+            _on_message: on_message,
+        })
+    }
+}
+
+impl Drop for WebSocketClient {
+    fn drop(&mut self) {
+        self.socket.set_onmessage(None);
+        let _ = self.socket.close();
+    }
+}
+
+impl DatagramSocket for WebSocketClient {
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.received.borrow_mut().pop_front() {
+            Some(payload) => {
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                Ok((len, self.peer))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if addr != self.peer {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.send(buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if self.socket.ready_state() == WebSocket::OPEN {
+            self.socket.send_with_u8_array(buf).map_err(js_error)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+fn js_error(error: wasm_bindgen::JsValue) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", error))
+}